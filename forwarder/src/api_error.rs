@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use protocol::{ApiError, ErrorCode};
+use reqwest::StatusCode;
+
+// the most we'll wait when the receiver asks us to back off
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+// for receivers that don't say how long
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+// the receiver is down, struggling or too busy for us; worth trying again later
+#[derive(Debug)]
+pub struct Unavailable(pub String);
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+// whether giving up on this error can wait until the receiver's back
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.is::<Unavailable>()
+            || cause
+                .downcast_ref::<reqwest::Error>()
+                .is_some_and(|err| err.is_connect() || err.is_timeout() || err.is_request())
+    })
+}
+
+// for the console, marked transient where it is
+pub fn into_error(err: &ApiError) -> anyhow::Error {
+    let message = describe(err);
+    match err.code {
+        ErrorCode::Internal | ErrorCode::RateLimited | ErrorCode::AtCapacity => {
+            Unavailable(message).into()
+        }
+        _ => anyhow::Error::msg(message),
+    }
+}
+
+// the response, or what the receiver said went wrong
+pub async fn check(resp: reqwest::Response) -> Result<reqwest::Response, ApiError> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    Err(read_error(resp).await)
+}
+
+// receivers from before typed errors only send a status, so make do with that
+pub async fn read_error(resp: reqwest::Response) -> ApiError {
+    let status = resp.status();
+    let retry_after_secs = resp
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    match resp.json::<ApiError>().await {
+        Ok(err) => err,
+        Err(_) => ApiError {
+            code: code_for(status),
+            message: format!("request failed with status {}", status),
+            retry_after_secs,
+        },
+    }
+}
+
+fn code_for(status: StatusCode) -> ErrorCode {
+    match status {
+        StatusCode::BAD_REQUEST => ErrorCode::InvalidRequest,
+        StatusCode::CONFLICT => ErrorCode::KeyInUse,
+        StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+        StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+        StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+        StatusCode::SERVICE_UNAVAILABLE => ErrorCode::AtCapacity,
+        status if status.is_server_error() => ErrorCode::Internal,
+        _ => ErrorCode::Unknown,
+    }
+}
+
+// how long to wait before trying again, if it's worth trying again at all
+pub fn retry_after(err: &ApiError) -> Option<Duration> {
+    match err.code {
+        ErrorCode::RateLimited | ErrorCode::AtCapacity => Some(
+            err.retry_after_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RETRY_AFTER)
+                .min(MAX_RETRY_AFTER),
+        ),
+        _ => None,
+    }
+}
+
+// what went wrong and what to do about it, for the console
+pub fn describe(err: &ApiError) -> String {
+    match err.code {
+        ErrorCode::InvalidKey => format!(
+            "the receiver rejected the key: {}. pick another --key",
+            err.message
+        ),
+        ErrorCode::KeyInUse => {
+            "the key is already in use on the receiver, pick another --key".to_string()
+        }
+        ErrorCode::IncompatibleVersion => format!(
+            "{}. upgrade whichever of the forwarder and receiver is older",
+            err.message
+        ),
+        ErrorCode::InvalidRequest => format!("the receiver rejected our request: {}", err.message),
+        ErrorCode::Unauthorized => {
+            "the receiver requires an api token, pass one with --token (or the FORWARDER_TOKEN env var)"
+                .to_string()
+        }
+        ErrorCode::Forbidden => {
+            "the receiver rejected our api token, check that --token is correct".to_string()
+        }
+        ErrorCode::RateLimited => {
+            "the receiver is rate limiting us, wait a minute and reconnect from spotify"
+                .to_string()
+        }
+        ErrorCode::AtCapacity => {
+            "the receiver is too busy to take another key, try again later".to_string()
+        }
+        ErrorCode::Internal | ErrorCode::Unknown => {
+            format!("the receiver failed: {}", err.message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_after() {
+        let err = |code, retry_after_secs| ApiError {
+            code,
+            message: String::new(),
+            retry_after_secs,
+        };
+        assert_eq!(
+            retry_after(&err(ErrorCode::RateLimited, Some(3))),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            retry_after(&err(ErrorCode::AtCapacity, None)),
+            Some(DEFAULT_RETRY_AFTER)
+        );
+        assert_eq!(
+            retry_after(&err(ErrorCode::RateLimited, Some(3600))),
+            Some(MAX_RETRY_AFTER)
+        );
+        assert_eq!(retry_after(&err(ErrorCode::KeyInUse, Some(3))), None);
+        assert_eq!(code_for(StatusCode::BAD_GATEWAY), ErrorCode::Internal);
+    }
+}
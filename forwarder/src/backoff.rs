@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+// exponential backoff with full jitter, so forwarders that lost the same receiver don't all come back at once
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            attempt: 0,
+        }
+    }
+
+    // somewhere between min and twice the last ceiling, up to max
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .min
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        if ceiling <= self.min {
+            return ceiling;
+        }
+        rand::thread_rng().gen_range(self.min..=ceiling)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        for ceiling in [2, 4, 8, 10, 10] {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(ceiling));
+        }
+        // doesn't overflow however long the receiver is gone
+        for _ in 0..100 {
+            assert!(backoff.next_delay() <= Duration::from_secs(10));
+        }
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+}
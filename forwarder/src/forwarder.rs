@@ -1,127 +1,507 @@
-use anyhow::Result;
-use futures_util::StreamExt;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures_util::{Stream, StreamExt};
 use librespot::discovery::Credentials;
 use reqwest::StatusCode;
 use sha1::{Digest, Sha1};
+use tokio::time::{Instant, Sleep};
+
+use protocol::{Capability, Envelope, ErrorCode, ForwardedCreds, PublicKeyResponse, VersionInfo};
+
+use crate::api_error;
+use crate::backoff::Backoff;
+use crate::keys::KeySource;
+use crate::link::{self, Link};
+use crate::session::Session;
+
+// how many times to try a fixed key that's in use, eg while the receiver notices our previous session closing
+const FIXED_KEY_ATTEMPTS: u32 = 5;
+// how many times to wait out a rate or capacity limit before giving up on the creds
+const BACKOFF_ATTEMPTS: u32 = 3;
 
 #[derive(Debug)]
 pub struct Forwarder {
     receiver_addr: String,
     http_client: reqwest::Client,
     device_name: String,
+    token: Option<String>,
+    keys: KeySource,
+    // the only discord user who may claim our keys, if set
+    discord_user: Option<u64>,
+    // pairs us with a discord user, who our keys then belong to
+    link: Option<Link>,
+    // what the receiver told us about itself at startup
+    receiver: VersionInfo,
+    // never send creds in the clear, even to receivers from before sealing
+    require_sealed: bool,
+    session: Option<Session>,
 }
 
 impl Forwarder {
-    pub async fn new(receiver_addr: String, device_name: String) -> Result<Self> {
+    pub async fn new(
+        receiver_addr: String,
+        device_name: String,
+        token: Option<String>,
+        keys: KeySource,
+        discord_user: Option<u64>,
+        link: Option<Link>,
+    ) -> Result<Self> {
         let http_client = reqwest::ClientBuilder::new()
             .connect_timeout(std::time::Duration::from_secs(5))
             .timeout(std::time::Duration::from_secs(5))
             .build()?;
+
+        // the receiver may just be restarting, so wait for it rather than giving up
+        let mut backoff = Backoff::default();
+        let receiver = loop {
+            match handshake(&http_client, &receiver_addr).await {
+                Ok(receiver) => break receiver,
+                Err(err) if api_error::is_transient(&err) => {
+                    let wait = backoff.next_delay();
+                    println!("{:#}, trying again in {}", err, format_duration(wait));
+                    tokio::time::sleep(wait).await;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        if discord_user.is_some() && !receiver.has(Capability::DiscordUser) {
+            anyhow::bail!("the receiver doesn't support --discord-user, upgrade it");
+        }
         Ok(Self {
+            receiver,
             http_client,
             device_name,
             receiver_addr,
+            token,
+            keys,
+            discord_user,
+            link,
+            require_sealed: false,
+            session: None,
         })
     }
 
-    pub async fn run(mut self) -> Result<()> {
+    // refuse to send creds to receivers too old to seal them
+    pub fn require_sealed(mut self, require: bool) -> Self {
+        self.require_sealed = require;
+        self
+    }
+
+    // links us to a discord user with /link, replacing any previous link
+    pub async fn pair(&mut self, path: std::path::PathBuf) -> Result<()> {
+        if !self.receiver.has(Capability::Link) {
+            anyhow::bail!("the receiver doesn't support --link, upgrade it");
+        }
+        let link = link::pair(
+            &self.http_client,
+            &self.receiver_addr,
+            self.token.as_deref(),
+            &self.device_name,
+            path,
+        )
+        .await?;
+        self.link = Some(link);
+        Ok(())
+    }
+
+    pub async fn run(self) -> Result<()> {
         // pretend to be a spotify receiver to grab credentials
 
         let device_id = device_id(&self.device_name);
 
-        let mut discovery = librespot::discovery::Discovery::builder(device_id)
+        let discovery = librespot::discovery::Discovery::builder(device_id)
             .name(self.device_name.clone())
             .launch()?;
 
         tracing::debug!("Starting discovery loop");
 
+        self.forward_all(discovery, Backoff::default()).await
+    }
+
+    // forwards each set of creds as it comes in. while the receiver can't be reached, the latest creds are held on
+    // to and sent again once it's back
+    async fn forward_all(
+        mut self,
+        mut credentials: impl Stream<Item = Credentials> + Unpin,
+        mut backoff: Backoff,
+    ) -> Result<()> {
+        let mut queued: Option<Credentials> = None;
+        let retry = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(retry);
+
         loop {
-            tokio::select! {
-                credentials = discovery.next() => {
-                    match credentials {
-                        Some(credentials) => {
-                            self.forward_creds(credentials).await?;
-                            tracing::debug!("forwarded");
+            let creds = tokio::select! {
+                creds = credentials.next() => {
+                    match creds {
+                        Some(creds) => {
+                            // newer creds replace any still waiting
+                            backoff.reset();
+                            creds
                         },
                         None => {
                             anyhow::bail!("Discovery stopped unexpectedly");
                         }
                     }
+                },
+                _ = &mut retry, if queued.is_some() => {
+                    queued.take().expect("only retried while queued")
                 },
                  _ = tokio::signal::ctrl_c() => {
                     break;
                 },
                 else => break,
-            }
+            };
+            // waiting out a busy receiver can take minutes, so don't hold up shutdown for it
+            queued = tokio::select! {
+                res = self.deliver(creds, &mut backoff, retry.as_mut()) => res?,
+                _ = tokio::signal::ctrl_c() => break,
+            };
         }
         tracing::info!("Gracefully shutting down");
+        if let Some(session) = self.session.take() {
+            session.close().await;
+        }
 
         Ok(())
     }
 
-    async fn forward_creds(&mut self, creds: Credentials) -> Result<()> {
-        // retry if the code is 409, as that means we picked a key that was already in use
-        let (key, status) = loop {
-            let key = generate_id();
-            let status = self
+    // forwards the creds and starts their session. if the receiver can't be reached they're handed back, to be
+    // tried again when `retry` goes off
+    async fn deliver(
+        &mut self,
+        creds: Credentials,
+        backoff: &mut Backoff,
+        retry: Pin<&mut Sleep>,
+    ) -> Result<Option<Credentials>> {
+        // a fixed key is still held by our previous session, so let it go first
+        if self.keys.is_fixed() {
+            self.close_session().await;
+        }
+        match self.forward_creds(creds.clone()).await {
+            Ok((key, secret)) => {
+                tracing::debug!("forwarded");
+                backoff.reset();
+                self.start_session(key, secret).await?;
+                Ok(None)
+            }
+            Err(err) if api_error::is_transient(&err) => {
+                let wait = backoff.next_delay();
+                println!(
+                    "couldn't forward credentials ({:#}), trying again in {}",
+                    err,
+                    format_duration(wait)
+                );
+                retry.reset(Instant::now() + wait);
+                Ok(Some(creds))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // the previous key's device is superseded by the new one, so let the receiver stop it
+    async fn start_session(&mut self, key: String, secret: Option<String>) -> Result<()> {
+        self.close_session().await;
+        self.session = Some(Session::start(
+            self.http_client.clone(),
+            &self.receiver_addr,
+            self.token.as_deref(),
+            key,
+            secret,
+        )?);
+        Ok(())
+    }
+
+    async fn close_session(&mut self) {
+        if let Some(session) = self.session.take() {
+            tracing::debug!(key = session.key(), "closing previous session");
+            session.close().await;
+        }
+    }
+
+    // the key, and the secret for its session socket
+    async fn forward_creds(&mut self, creds: Credentials) -> Result<(String, Option<String>)> {
+        let creds = self.seal_creds(creds).await?;
+        // random keys that are already in use just get rerolled; a fixed key might be held by our own previous
+        // session until the receiver notices it closing. rate and capacity limits are waited out for a bit
+        let mut attempts = 0;
+        let mut backoffs = 0;
+        let (key, resp) = loop {
+            let key = self.keys.next_key();
+            let resp = self
                 .perform_forward_creds_req(creds.clone(), key.clone())
                 .await?;
-            match status {
-                StatusCode::CONFLICT => {
-                    tracing::debug!("key conflict, retrying");
+            let err = match api_error::check(resp).await {
+                Ok(resp) => break (key, resp),
+                Err(err) => err,
+            };
+            tracing::debug!(?key, ?err, "forward creds failed");
+            match err.code {
+                ErrorCode::KeyInUse if self.keys.is_fixed() => {
+                    attempts += 1;
+                    if attempts >= FIXED_KEY_ATTEMPTS {
+                        anyhow::bail!(
+                            "key {:?} is already in use on the receiver, pick another --key",
+                            key
+                        );
+                    }
+                    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                 }
-                _ => break (key, status),
+                ErrorCode::KeyInUse => {}
+                _ => match api_error::retry_after(&err) {
+                    Some(wait) if backoffs < BACKOFF_ATTEMPTS => {
+                        backoffs += 1;
+                        println!(
+                            "{}, trying again in {}",
+                            match err.code {
+                                ErrorCode::AtCapacity =>
+                                    "the receiver is too busy to take another key",
+                                _ => "the receiver is rate limiting us",
+                            },
+                            format_duration(wait)
+                        );
+                        tokio::time::sleep(wait).await;
+                    }
+                    _ => return Err(api_error::into_error(&err)),
+                },
             }
         };
+        let resp: protocol::ForwardCredsResponse = resp.json().await?;
 
         println!(
-            "\n\n****\tyour key is: {:?} - run the following command in discord: /play_spotify {}\t****\n\n",
-            key, key
+            "\n\n****\tyour key for {} is: {:?} - run the following command in discord: /play_spotify {}\t****\n****\tthe key is valid for {}\t****\n\n",
+            self.device_name,
+            key,
+            key,
+            format_duration(std::time::Duration::from_secs(resp.expires_in_secs))
         );
+        if let Some(user) = resp.linked_user {
+            println!(
+                "****\tor, as discord user {}, just /play_spotify\t****\n\n",
+                user
+            );
+        } else if let Some(user) = self.discord_user {
+            println!("****\tonly discord user {} can claim it\t****\n\n", user);
+        }
+        if resp.linked_user.is_none() {
+            if let Some(link) = self.link.take() {
+                println!("the receiver doesn't recognise this forwarder's link anymore, run with --link to link it again");
+                link.forget();
+            }
+        }
+
+        Ok((key, resp.session_secret))
+    }
 
-        if status != reqwest::StatusCode::OK {
-            anyhow::bail!("forward creds failed with status: {:?}", status);
+    // seals the creds for the receiver, unless it's from before sealing. fetches the key every time, as the
+    // receiver makes a new one whenever it restarts
+    async fn seal_creds(&self, creds: Credentials) -> Result<ForwardedCreds> {
+        if !self.receiver.has(Capability::SealedCreds) {
+            if self.require_sealed {
+                anyhow::bail!(
+                    "the receiver is too old to seal credentials for, and --require-sealed is set. upgrade the receiver"
+                );
+            }
+            // anything between us and the receiver could be pretending to be an old one
+            println!(
+                "\n\n****\tWARNING: the receiver is too old to seal credentials for, sending your spotify credentials unsealed. pass --require-sealed to refuse\t****\n\n"
+            );
+            return Ok(ForwardedCreds::Plain(creds));
         }
-        Ok(())
+        let mut req = self
+            .http_client
+            .get(self.receiver_addr.clone() + "/api/public_key");
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let resp: PublicKeyResponse = checked(req.send().await?).await?.json().await?;
+        Ok(ForwardedCreds::Sealed(protocol::sealed::seal(
+            &creds,
+            &resp.public_key,
+        )?))
     }
 
     async fn perform_forward_creds_req(
         &mut self,
-        creds: Credentials,
+        creds: ForwardedCreds,
         key: String,
-    ) -> Result<StatusCode> {
-        let resp = self
+    ) -> Result<reqwest::Response> {
+        let mut req = self
             .http_client
-            .post(self.receiver_addr.clone() + "/api/forward_creds")
-            .json(&protocol::ForwardCreds {
-                device_name: self.device_name.clone(),
-                creds,
-                key,
-            })
+            .post(self.receiver_addr.clone() + "/api/forward_creds");
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let resp = req
+            .json(&Envelope::new(
+                env!("CARGO_PKG_VERSION"),
+                protocol::ForwardCreds {
+                    device_name: self.device_name.clone(),
+                    creds,
+                    key,
+                    discord_user: self.discord_user,
+                    link_token: self.link.as_ref().map(|link| link.token().to_string()),
+                },
+            ))
             .send()
             .await?;
-        let status = resp.status();
-        tracing::debug!(?resp, ?status, "forward creds response");
-        Ok(status)
+        tracing::debug!(?resp, status = ?resp.status(), "forward creds response");
+        Ok(resp)
+    }
+}
+
+// checks we can talk to the receiver at all, before there are any creds to lose
+async fn handshake(http_client: &reqwest::Client, receiver_addr: &str) -> Result<VersionInfo> {
+    let resp = http_client
+        .get(format!(
+            "{}/api/version",
+            receiver_addr.trim_end_matches('/')
+        ))
+        .send()
+        .await
+        .with_context(|| format!("failed to reach the receiver at {}", receiver_addr))?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        anyhow::bail!(
+            "the receiver at {} is older than this forwarder and doesn't say which version it is, upgrade the receiver",
+            receiver_addr
+        );
     }
+    let info: VersionInfo = checked(resp).await?.json().await?;
+    info.check_compatible().map_err(anyhow::Error::msg)?;
+    tracing::debug!(?info, "receiver version");
+    Ok(info)
+}
+
+// for requests where any error is fatal
+pub(crate) async fn checked(resp: reqwest::Response) -> Result<reqwest::Response> {
+    api_error::check(resp)
+        .await
+        .map_err(|err| api_error::into_error(&err))
 }
 
 fn device_id(name: &str) -> String {
     hex::encode(Sha1::digest(name.as_bytes()))
 }
 
-fn generate_id() -> String {
-    use once_cell::sync::Lazy;
-    use rand::seq::SliceRandom;
+fn format_duration(d: std::time::Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 60, secs % 60) {
+        (0, s) => format!("{}s", s),
+        (m, 0) => format!("{}m", m),
+        (m, s) => format!("{}m{}s", m, s),
+    }
+}
 
-    static BASE_WORDS: Lazy<Vec<&'static str>> =
-        Lazy::new(|| vec!["brad", "bro", "beer", "buck", "beans", "bird", "brain"]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing, Json, Router};
+    use protocol::{ApiError, ForwardCredsResponse, PROTOCOL_VERSION};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::{oneshot, Notify};
 
-    let mut rng = rand::thread_rng();
+    // a stand-in receiver that fails its first forward
+    #[derive(Clone, Default)]
+    struct Flaky {
+        forwards: Arc<AtomicU32>,
+        forwarded: Arc<Notify>,
+    }
 
-    let word = BASE_WORDS.choose(&mut rng).unwrap();
+    async fn version() -> Json<VersionInfo> {
+        Json(VersionInfo {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: 0,
+            receiver_version: "test".to_string(),
+            capabilities: vec![],
+        })
+    }
 
-    let num = rand::random::<u32>() % 100;
+    async fn forward_creds(
+        State(flaky): State<Flaky>,
+    ) -> Result<Json<ForwardCredsResponse>, (axum::http::StatusCode, Json<ApiError>)> {
+        if flaky.forwards.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Err((
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ApiError {
+                    code: ErrorCode::Internal,
+                    message: "oops".to_string(),
+                    retry_after_secs: None,
+                }),
+            ));
+        }
+        flaky.forwarded.notify_one();
+        Ok(Json(ForwardCredsResponse {
+            expires_in_secs: 600,
+            linked_user: None,
+            session_secret: None,
+        }))
+    }
+
+    // serves until the sender is used or dropped
+    fn serve(listener: std::net::TcpListener, flaky: Flaky) -> oneshot::Sender<()> {
+        let app = Router::new()
+            .route("/api/version", routing::get(version))
+            .route("/api/forward_creds", routing::post(forward_creds))
+            .with_state(flaky);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(async {
+                let _ = stopped.await;
+            });
+        tokio::spawn(server);
+        stop
+    }
 
-    format!("{}{}", word, num)
+    #[tokio::test]
+    async fn test_receiver_flapping() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let flaky = Flaky::default();
+
+        // the receiver isn't up yet when the forwarder starts
+        let starting = tokio::spawn(Forwarder::new(
+            format!("http://{}", addr),
+            "test".to_string(),
+            None,
+            KeySource::Fixed("flaky-key".to_string()),
+            None,
+            None,
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!starting.is_finished());
+        let stop = serve(std::net::TcpListener::bind(addr).unwrap(), flaky.clone());
+        let forwarder = tokio::time::timeout(Duration::from_secs(10), starting)
+            .await
+            .expect("the forwarder never reached the receiver")
+            .unwrap()
+            .unwrap();
+
+        // the receiver goes away just as spotify hands over creds
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let creds = futures_util::stream::iter([Credentials::with_password("user", "pass")])
+            .chain(futures_util::stream::pending());
+        let forwarding = tokio::spawn(forwarder.forward_all(
+            creds,
+            Backoff::new(Duration::from_millis(10), Duration::from_millis(50)),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(flaky.forwards.load(Ordering::SeqCst), 0);
+        assert!(!forwarding.is_finished());
+
+        // then comes back, failing once more before it takes the creds
+        let _stop = serve(std::net::TcpListener::bind(addr).unwrap(), flaky.clone());
+        tokio::time::timeout(Duration::from_secs(10), flaky.forwarded.notified())
+            .await
+            .expect("creds were never forwarded");
+        assert_eq!(flaky.forwards.load(Ordering::SeqCst), 2);
+        assert!(!forwarding.is_finished());
+        forwarding.abort();
+    }
 }
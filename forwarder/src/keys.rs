@@ -0,0 +1,132 @@
+use anyhow::{Context, Result};
+use rand::seq::SliceRandom;
+
+// enough that keys can't be guessed in the few minutes they're pending
+const BUILTIN_WORDS: &str = include_str!("words.txt");
+// a custom wordlist shorter than this makes for keys that are too easy to guess
+const MIN_WORDS: usize = 64;
+
+// where stream keys come from
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    // the same key every time, so people can keep using it across spotify reconnects
+    Fixed(String),
+    Random(KeyGenerator),
+}
+
+impl KeySource {
+    pub fn is_fixed(&self) -> bool {
+        matches!(self, KeySource::Fixed(_))
+    }
+
+    pub fn next_key(&self) -> String {
+        match self {
+            KeySource::Fixed(key) => key.clone(),
+            KeySource::Random(generator) => generator.generate(),
+        }
+    }
+}
+
+// random keys of a few words from a wordlist, like "otter-maple-comet"
+#[derive(Debug, Clone)]
+pub struct KeyGenerator {
+    words: Vec<String>,
+    count: usize,
+}
+
+impl KeyGenerator {
+    // uses the builtin wordlist unless given a file with one word per line
+    pub fn new(wordlist: Option<&str>, count: usize) -> Result<Self> {
+        let words = match wordlist {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read wordlist {}", path))?,
+            None => BUILTIN_WORDS.to_string(),
+        };
+        Self::from_words(&words, count)
+    }
+
+    fn from_words(words: &str, count: usize) -> Result<Self> {
+        let mut words: Vec<String> = words
+            .lines()
+            .map(|word| word.trim().to_lowercase())
+            .filter(|word| !word.is_empty())
+            .collect();
+        words.sort();
+        words.dedup();
+        if let Some(word) = words.iter().find(|word| {
+            !word
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit())
+        }) {
+            anyhow::bail!(
+                "wordlist contains {:?}, words may only contain lowercase letters and digits",
+                word
+            );
+        }
+        if words.len() < MIN_WORDS {
+            anyhow::bail!(
+                "wordlist only has {} distinct words, it needs at least {}",
+                words.len(),
+                MIN_WORDS
+            );
+        }
+        anyhow::ensure!(count > 0, "keys need at least one word");
+
+        let generator = Self { words, count };
+        let shortest = generator.words.iter().map(|w| w.len()).min().unwrap_or(0);
+        let longest = generator.words.iter().map(|w| w.len()).max().unwrap_or(0);
+        anyhow::ensure!(
+            shortest * count + count > protocol::KEY_MIN_LEN,
+            "keys of {} words could be shorter than {} characters",
+            count,
+            protocol::KEY_MIN_LEN
+        );
+        anyhow::ensure!(
+            longest * count + count - 1 <= protocol::KEY_MAX_LEN,
+            "keys of {} words could be longer than {} characters",
+            count,
+            protocol::KEY_MAX_LEN
+        );
+        tracing::debug!(
+            words = generator.words.len(),
+            count,
+            bits = generator.entropy_bits(),
+            "key generator ready"
+        );
+        Ok(generator)
+    }
+
+    pub fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        (0..self.count)
+            .map(|_| self.words.choose(&mut rng).unwrap().as_str())
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+
+    // how hard the keys are to guess
+    pub fn entropy_bits(&self) -> f64 {
+        self.count as f64 * (self.words.len() as f64).log2()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generator() {
+        let generator = KeyGenerator::new(None, 3).unwrap();
+        assert!(generator.entropy_bits() > 27.0);
+        for _ in 0..100 {
+            let key = generator.generate();
+            assert!(protocol::validate_key(&key).is_ok(), "{key:?}");
+            assert_eq!(key.split('-').count(), 3);
+        }
+
+        let short: String = (0..10).map(|i| format!("word{i}\n")).collect();
+        assert!(KeyGenerator::from_words(&short, 3).is_err());
+        let bad: String = BUILTIN_WORDS.to_string() + "not a word\n";
+        assert!(KeyGenerator::from_words(&bad, 3).is_err());
+    }
+}
@@ -1,2 +1,9 @@
+pub mod api_error;
+pub mod backoff;
 pub mod forwarder;
+pub mod keys;
+pub mod link;
+pub mod receivers;
+pub mod session;
+pub mod status;
 pub use crate::forwarder::Forwarder;
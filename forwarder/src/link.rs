@@ -0,0 +1,146 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use protocol::{LinkRequest, LinkResponse, LinkStatus, LinkStatusRequest};
+
+use crate::forwarder::checked;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// the token that pairs this forwarder with a discord user, and where it's kept
+pub struct Link {
+    path: PathBuf,
+    token: String,
+}
+
+// not the token, it'd end up in logs
+impl std::fmt::Debug for Link {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Link").field("path", &self.path).finish()
+    }
+}
+
+impl Link {
+    // None if this forwarder hasn't been linked
+    pub fn load(path: PathBuf) -> Result<Option<Self>> {
+        match std::fs::read_to_string(&path) {
+            Ok(token) if !token.trim().is_empty() => Ok(Some(Self {
+                token: token.trim().to_string(),
+                path,
+            })),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => {
+                Err(err).with_context(|| format!("failed to read link file {}", path.display()))
+            }
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    // for when the receiver doesn't recognise the token anymore
+    pub fn forget(self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::warn!(path = ?self.path, ?err, "failed to remove link file");
+        }
+    }
+}
+
+// it's as good as the spotify account, so only we get to read it
+fn write_token(path: &Path, token: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(token.as_bytes())
+}
+
+// in the home directory, so it's found wherever the forwarder is run from
+pub fn default_path() -> PathBuf {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"));
+    match home {
+        Some(home) => Path::new(&home).join(".spotify-remote-link"),
+        None => PathBuf::from(".spotify-remote-link"),
+    }
+}
+
+// asks the receiver for a pairing code, waits for someone to /link it in discord, then saves the token
+pub async fn pair(
+    http_client: &reqwest::Client,
+    receiver_addr: &str,
+    api_token: Option<&str>,
+    device_name: &str,
+    path: PathBuf,
+) -> Result<Link> {
+    let receiver_addr = receiver_addr.trim_end_matches('/');
+    let mut req = http_client.post(format!("{}/api/link", receiver_addr));
+    if let Some(api_token) = api_token {
+        req = req.bearer_auth(api_token);
+    }
+    let resp = req
+        .json(&LinkRequest {
+            device_name: device_name.to_string(),
+        })
+        .send()
+        .await?;
+    let resp: LinkResponse = checked(resp)
+        .await
+        .context("failed to start linking")?
+        .json()
+        .await?;
+
+    println!(
+        "\n\n****\tto link {} to your discord account, run the following command in discord: /link {}\t****\n****\tthe code is valid for {}s\t****\n\n",
+        device_name,
+        resp.code, resp.expires_in_secs
+    );
+
+    let status = loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let mut req = http_client.post(format!("{}/api/link/status", receiver_addr));
+        if let Some(api_token) = api_token {
+            req = req.bearer_auth(api_token);
+        }
+        let status = req
+            .json(&LinkStatusRequest {
+                token: resp.token.clone(),
+            })
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        let status: LinkStatus = match status {
+            Ok(resp) => resp.json().await?,
+            Err(err) => {
+                tracing::debug!(?err, "failed to get link status");
+                continue;
+            }
+        };
+        if !matches!(status, LinkStatus::Pending { .. }) {
+            break status;
+        }
+    };
+
+    let LinkStatus::Linked { discord_user } = status else {
+        anyhow::bail!(
+            "the code expired before anyone used it, run with --link again for a new one"
+        );
+    };
+    write_token(&path, &resp.token)
+        .with_context(|| format!("failed to write link file {}", path.display()))?;
+    println!(
+        "linked to discord user {}, /play_spotify will find this forwarder's streams without a key",
+        discord_user
+    );
+    Ok(Link {
+        path,
+        token: resp.token,
+    })
+}
@@ -1,5 +1,11 @@
-use anyhow::Result;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
 use clap::Parser;
+use forwarder::keys::{KeyGenerator, KeySource};
+use forwarder::link::{self, Link};
+use forwarder::receivers::{self, Receiver};
+use forwarder::Forwarder;
 
 #[derive(Debug, Parser)]
 struct Options {
@@ -7,9 +13,17 @@ struct Options {
         short = 'a',
         long,
         env,
-        help = "address of the receiver server, eg http://localhost:8080"
+        value_delimiter = ',',
+        required_unless_present = "receivers",
+        help = "address of the receiver server, eg http://localhost:8080. give several, as name=address, for a spotify device per receiver"
     )]
-    receiver_addr: String,
+    receiver_addr: Vec<Receiver>,
+    #[clap(
+        long,
+        env = "FORWARDER_RECEIVERS",
+        help = "file of receivers to forward to, one `[name=]address [token]` per line"
+    )]
+    receivers: Option<String>,
     #[clap(
         short = 'n',
         long,
@@ -18,6 +32,62 @@ struct Options {
         help = "name of the device"
     )]
     device_name: String,
+    #[clap(
+        short = 't',
+        long,
+        env = "FORWARDER_TOKEN",
+        help = "api token to authenticate with the receiver"
+    )]
+    token: Option<String>,
+    #[clap(
+        short = 'k',
+        long,
+        env = "FORWARDER_KEY",
+        value_parser = parse_key,
+        help = "use this key every time instead of a random one, eg so it can be bookmarked"
+    )]
+    key: Option<String>,
+    #[clap(
+        long,
+        default_value_t = 3,
+        conflicts_with = "key",
+        help = "how many words random keys are made of"
+    )]
+    key_words: usize,
+    #[clap(
+        long,
+        conflicts_with = "key",
+        help = "file to pick the words of random keys from, one per line"
+    )]
+    wordlist: Option<String>,
+    #[clap(
+        long,
+        env = "FORWARDER_DISCORD_USER",
+        help = "id of the only discord user allowed to claim our keys"
+    )]
+    discord_user: Option<u64>,
+    #[clap(
+        long,
+        help = "link this forwarder to your discord account with /link, so /play_spotify finds its streams without a key"
+    )]
+    link: bool,
+    #[clap(
+        long,
+        env = "FORWARDER_LINK_FILE",
+        help = "where to keep the link, defaults to .spotify-remote-link in the home directory"
+    )]
+    link_file: Option<String>,
+    #[clap(
+        long,
+        env = "FORWARDER_REQUIRE_SEALED",
+        help = "refuse to send credentials to receivers too old to seal them for"
+    )]
+    require_sealed: bool,
+}
+
+fn parse_key(key: &str) -> Result<String, String> {
+    protocol::validate_key(key)?;
+    Ok(key.to_string())
 }
 
 #[tokio::main]
@@ -26,9 +96,101 @@ async fn main() -> Result<()> {
 
     let opts = Options::parse();
 
-    let forwarder = forwarder::Forwarder::new(opts.receiver_addr, opts.device_name).await?;
+    let keys = match opts.key {
+        Some(key) => KeySource::Fixed(key),
+        None => KeySource::Random(KeyGenerator::new(opts.wordlist.as_deref(), opts.key_words)?),
+    };
 
-    forwarder.run().await?;
+    let mut receivers = opts.receiver_addr;
+    if let Some(path) = &opts.receivers {
+        receivers.extend(receivers::load(path)?);
+    }
+    if receivers.is_empty() {
+        anyhow::bail!("no receivers to forward to");
+    }
+    receivers::check_unique(&receivers)?;
+    let several = receivers.len() > 1;
+
+    let link_file = opts
+        .link_file
+        .map(PathBuf::from)
+        .unwrap_or_else(link::default_path);
+
+    // each receiver gets on with it by itself, so one that's down or misconfigured doesn't take the others with it
+    let mut tasks = Vec::with_capacity(receivers.len());
+    for receiver in receivers {
+        let settings = ForwarderSettings {
+            device_name: receiver.device_name(&opts.device_name, several),
+            token: receiver.token.clone().or_else(|| opts.token.clone()),
+            link_file: receiver.link_path(&link_file, several),
+            keys: keys.clone(),
+            discord_user: opts.discord_user,
+            link: opts.link,
+            require_sealed: opts.require_sealed,
+        };
+        let name = receiver.name.clone();
+        tasks.push((name, tokio::spawn(forward_to(receiver, settings))));
+    }
+
+    let count = tasks.len();
+    let mut failed = 0;
+    for (name, task) in tasks {
+        let res = task.await.map_err(anyhow::Error::from).and_then(|res| res);
+        if let Err(err) = res {
+            if !several {
+                return Err(err);
+            }
+            failed += 1;
+            tracing::error!(receiver = name, "stopped forwarding: {:#}", err);
+        }
+    }
+    if failed == count {
+        anyhow::bail!("couldn't forward to any receiver");
+    }
 
     Ok(())
 }
+
+// what every receiver's forwarder is set up with
+struct ForwarderSettings {
+    device_name: String,
+    token: Option<String>,
+    link_file: PathBuf,
+    keys: KeySource,
+    discord_user: Option<u64>,
+    link: bool,
+    require_sealed: bool,
+}
+
+async fn forward_to(receiver: Receiver, settings: ForwarderSettings) -> Result<()> {
+    // linking again replaces the old link, so don't bother loading it
+    let link = if settings.link {
+        None
+    } else {
+        Link::load(settings.link_file.clone())?
+    };
+
+    let setup = async {
+        let mut forwarder = Forwarder::new(
+            receiver.addr.clone(),
+            settings.device_name,
+            settings.token,
+            settings.keys,
+            settings.discord_user,
+            link,
+        )
+        .await
+        .with_context(|| format!("failed to connect to receiver {}", receiver.name))?
+        .require_sealed(settings.require_sealed);
+        if settings.link {
+            forwarder.pair(settings.link_file).await?;
+        }
+        anyhow::Ok(forwarder)
+    };
+    // the forwarder only listens for ctrl-c once it's running
+    let forwarder = tokio::select! {
+        forwarder = setup => forwarder?,
+        _ = tokio::signal::ctrl_c() => return Ok(()),
+    };
+    forwarder.run().await
+}
@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+// a receiver to forward to. with several, each gets its own spotify device, so picking the device in spotify picks
+// the discord bot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Receiver {
+    // tells the devices apart, and their link files. defaults to the receiver's host
+    pub name: String,
+    pub addr: String,
+    // overrides --token, for receivers that don't share one
+    pub token: Option<String>,
+}
+
+// `[name=]addr`, as given to --receiver-addr
+impl FromStr for Receiver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, addr) = match s.split_once('=') {
+            // urls can have = in their query, but not before the scheme
+            Some((name, addr)) if !name.contains("://") => (Some(name.trim()), addr.trim()),
+            _ => (None, s.trim()),
+        };
+        let url =
+            reqwest::Url::parse(addr).map_err(|err| format!("bad address {:?}: {}", addr, err))?;
+        let name = match name {
+            Some(name) => name.to_string(),
+            // eg [::1]
+            None => url
+                .host_str()
+                .unwrap_or_default()
+                .chars()
+                .map(|c| if is_name_char(c) { c } else { '-' })
+                .collect(),
+        };
+        // it ends up in a file name
+        if name.is_empty() || !name.chars().all(is_name_char) {
+            return Err(format!(
+                "bad receiver name {:?}, use letters, digits, '.', '_' and '-'",
+                name
+            ));
+        }
+        Ok(Self {
+            name,
+            addr: addr.to_string(),
+            token: None,
+        })
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')
+}
+
+impl Receiver {
+    // the spotify device for this receiver. only named after it when there are several to pick from
+    pub fn device_name(&self, base: &str, several: bool) -> String {
+        if several {
+            format!("{} ({})", base, self.name)
+        } else {
+            base.to_string()
+        }
+    }
+
+    // links are made with one receiver, so each needs its own file
+    pub fn link_path(&self, base: &Path, several: bool) -> PathBuf {
+        if several {
+            let mut path = base.as_os_str().to_owned();
+            path.push(format!("-{}", self.name));
+            PathBuf::from(path)
+        } else {
+            base.to_path_buf()
+        }
+    }
+}
+
+// one receiver per line, `[name=]addr [token]`. blank lines and lines starting with # are skipped
+pub fn load(path: &str) -> Result<Vec<Receiver>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read receivers file {}", path))?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let mut parts = line.split_whitespace();
+            let mut receiver: Receiver = parts
+                .next()
+                .unwrap_or_default()
+                .parse()
+                .map_err(|err| anyhow::anyhow!("{}:{}: {}", path, i + 1, err))?;
+            receiver.token = parts.next().map(str::to_string);
+            if parts.next().is_some() {
+                anyhow::bail!("{}:{}: expected `[name=]addr [token]`", path, i + 1);
+            }
+            Ok(receiver)
+        })
+        .collect()
+}
+
+// the names end up in device names, which spotify needs to tell apart
+pub fn check_unique(receivers: &[Receiver]) -> Result<()> {
+    let mut names = HashSet::new();
+    for receiver in receivers {
+        if !names.insert(&receiver.name) {
+            anyhow::bail!(
+                "more than one receiver is called {:?}, name them with name=addr",
+                receiver.name
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let receiver: Receiver = "http://localhost:8080".parse().unwrap();
+        assert_eq!(receiver.name, "localhost");
+        assert_eq!(receiver.addr, "http://localhost:8080");
+        assert_eq!(receiver.device_name("danube", false), "danube");
+
+        let receiver: Receiver = "gaming=https://bot.example.com/?a=b".parse().unwrap();
+        assert_eq!(receiver.name, "gaming");
+        assert_eq!(receiver.addr, "https://bot.example.com/?a=b");
+        assert_eq!(receiver.device_name("danube", true), "danube (gaming)");
+        assert_eq!(
+            receiver.link_path(Path::new("/home/me/.link"), true),
+            PathBuf::from("/home/me/.link-gaming")
+        );
+        assert_eq!(
+            "https://bot.example.com/?a=b"
+                .parse::<Receiver>()
+                .unwrap()
+                .name,
+            "bot.example.com"
+        );
+
+        assert!("not a url".parse::<Receiver>().is_err());
+        assert!("=http://localhost".parse::<Receiver>().is_err());
+        assert!("a/../../x=http://localhost".parse::<Receiver>().is_err());
+        assert!("a b=http://localhost".parse::<Receiver>().is_err());
+        assert_eq!(
+            "http://[::1]:8080".parse::<Receiver>().unwrap().name,
+            "---1-"
+        );
+
+        let receivers = [
+            "http://localhost:8080".parse().unwrap(),
+            "http://localhost:8081".parse().unwrap(),
+        ];
+        assert!(check_unique(&receivers).is_err());
+        assert!(check_unique(&receivers[..1]).is_ok());
+    }
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{
+    self, client::IntoClientRequest, http::header, http::HeaderMap, http::StatusCode, Message,
+};
+
+use crate::backoff::Backoff;
+use crate::status;
+
+// a websocket held open to the receiver for as long as we're alive, so it can stop playback of our key when we go
+// away. also keeps the user posted on what's become of the key.
+#[derive(Debug)]
+pub struct Session {
+    key: String,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+    status_task: JoinHandle<()>,
+}
+
+impl Session {
+    pub fn start(
+        http_client: reqwest::Client,
+        receiver_addr: &str,
+        token: Option<&str>,
+        key: String,
+        secret: Option<String>,
+    ) -> Result<Self> {
+        let url = session_url(receiver_addr, &key)?;
+        let mut headers = HeaderMap::new();
+        if let Some(token) = token {
+            headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+        }
+        if let Some(secret) = secret {
+            headers.insert(protocol::SESSION_SECRET_HEADER, secret.parse()?);
+        }
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(keep_alive(url, headers, key.clone(), shutdown_rx));
+
+        let status_task = tokio::spawn(status::watch(
+            http_client,
+            receiver_addr.to_string(),
+            token.map(str::to_string),
+            key.clone(),
+        ));
+
+        Ok(Self {
+            key,
+            shutdown,
+            task,
+            status_task,
+        })
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // tell the receiver we're going away and wait for the socket to close
+    pub async fn close(self) {
+        self.status_task.abort();
+        let _ = self.shutdown.send(());
+        if let Err(err) = tokio::time::timeout(std::time::Duration::from_secs(5), self.task).await {
+            tracing::warn!(key = ?self.key, ?err, "timed out closing session");
+        }
+    }
+}
+
+// holds the session open, reconnecting after network blips. the receiver gives us a little while to come back
+// before it stops playback, and turns us away once the key isn't ours anymore
+async fn keep_alive(
+    url: String,
+    headers: HeaderMap,
+    key: String,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut backoff = Backoff::new(Duration::from_millis(500), protocol::HEARTBEAT_INTERVAL);
+    loop {
+        let req = url.as_str().into_client_request().map(|mut req| {
+            req.headers_mut().extend(headers.clone());
+            req
+        });
+        let res = match req {
+            Ok(req) => heartbeat(req, &mut shutdown, &mut backoff).await,
+            Err(err) => Err(err.into()),
+        };
+        let err = match res {
+            Ok(()) => {
+                tracing::debug!(?key, "session closed");
+                return;
+            }
+            Err(err) => err,
+        };
+        // the receiver doesn't know our key or secret anymore, eg it restarted or gave up on us
+        if let Some(tungstenite::Error::Http(resp)) = err.downcast_ref::<tungstenite::Error>() {
+            if resp.status().is_client_error() && resp.status() != StatusCode::TOO_MANY_REQUESTS {
+                tracing::warn!(
+                    ?key,
+                    status = ?resp.status(),
+                    "receiver turned down our session, playback of this key will stop"
+                );
+                return;
+            }
+        }
+        let wait = backoff.next_delay();
+        tracing::warn!(
+            ?key,
+            ?err,
+            ?wait,
+            "lost session with receiver, reconnecting"
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = &mut shutdown => return,
+        }
+    }
+}
+
+async fn heartbeat(
+    req: tungstenite::handshake::client::Request,
+    shutdown: &mut oneshot::Receiver<()>,
+    backoff: &mut Backoff,
+) -> Result<()> {
+    let (mut ws, _) = tokio_tungstenite::connect_async(req).await?;
+    tracing::debug!("session established");
+    backoff.reset();
+
+    let mut interval = tokio::time::interval(protocol::HEARTBEAT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                ws.send(Message::Ping(Vec::new())).await?;
+            }
+            msg = ws.next() => match msg {
+                None | Some(Ok(Message::Close(_))) => anyhow::bail!("receiver closed the session"),
+                Some(Err(err)) => return Err(err.into()),
+                Some(Ok(_)) => {}
+            },
+            _ = &mut *shutdown => {
+                ws.close(None).await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn session_url(receiver_addr: &str, key: &str) -> Result<String> {
+    let base = if let Some(rest) = receiver_addr.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = receiver_addr.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        anyhow::bail!("receiver address must start with http:// or https://")
+    };
+    Ok(format!(
+        "{}/api/session/{}",
+        base.trim_end_matches('/'),
+        key
+    ))
+}
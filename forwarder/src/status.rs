@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use protocol::{KeyStatus, VoiceLocation};
+
+use crate::forwarder::checked;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+
+// polls the receiver for what's become of our key and prints it whenever that changes, so the user knows their
+// stream actually started. returns once the key is done with.
+pub async fn watch(
+    http_client: reqwest::Client,
+    receiver_addr: String,
+    token: Option<String>,
+    key: String,
+) {
+    let url = format!(
+        "{}/api/key/{}/status",
+        receiver_addr.trim_end_matches('/'),
+        key
+    );
+    let mut last = None;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let status = match fetch(&http_client, &url, token.as_deref()).await {
+            Ok(status) => status,
+            Err(err) => {
+                tracing::debug!(?key, ?err, "failed to get key status");
+                continue;
+            }
+        };
+
+        let description = describe(&key, &status);
+        if last.as_ref() != Some(&description) {
+            println!("{}", description);
+            last = Some(description);
+        }
+        if matches!(
+            status,
+            KeyStatus::Expired | KeyStatus::Stopped | KeyStatus::Unknown
+        ) {
+            return;
+        }
+    }
+}
+
+async fn fetch(http_client: &reqwest::Client, url: &str, token: Option<&str>) -> Result<KeyStatus> {
+    let mut req = http_client.get(url);
+    if let Some(token) = token {
+        req = req.bearer_auth(token);
+    }
+    let resp = checked(req.send().await?).await?;
+    Ok(resp.json().await?)
+}
+
+fn describe(key: &str, status: &KeyStatus) -> String {
+    match status {
+        // leaves out the time left, which changes every poll
+        KeyStatus::Pending { .. } => {
+            format!("{key}: waiting for someone to run /play_spotify {key} in discord")
+        }
+        KeyStatus::Claimed { location } => format!(
+            "{key}: claimed in {}, waiting for spotify to start playing",
+            describe_location(location)
+        ),
+        KeyStatus::Playing { location, track } => {
+            let verb = if track.paused { "paused on" } else { "playing" };
+            let by = if track.artists.is_empty() {
+                String::new()
+            } else {
+                format!(" by {}", track.artists.join(", "))
+            };
+            format!(
+                "{key}: {verb} {}{by} in {}",
+                track.title,
+                describe_location(location)
+            )
+        }
+        KeyStatus::Expired => format!(
+            "{key}: expired before anyone used it, reconnect to the device in spotify to get a new key"
+        ),
+        KeyStatus::Stopped => format!("{key}: playback stopped"),
+        KeyStatus::Unknown => format!("{key}: the receiver doesn't know this key anymore"),
+    }
+}
+
+fn describe_location(location: &VoiceLocation) -> String {
+    format!("{} / {}", location.guild_name, location.channel_name)
+}
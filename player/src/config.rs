@@ -0,0 +1,71 @@
+use clap::{ArgAction, Args, ValueEnum};
+use librespot::playback::config::{self as spotify_config, PlayerConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Bitrate {
+    #[value(name = "96")]
+    Kbps96,
+    #[value(name = "160")]
+    Kbps160,
+    #[value(name = "320")]
+    Kbps320,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NormalisationMethod {
+    Basic,
+    Dynamic,
+}
+
+// trade bandwidth against quality
+#[derive(Debug, Clone, Args)]
+pub struct AudioOptions {
+    #[clap(long, env, value_enum, default_value = "160", help = "bitrate in kbps")]
+    pub bitrate: Bitrate,
+    #[clap(long, env, help = "normalise track volume")]
+    pub normalisation: bool,
+    #[clap(
+        long,
+        env,
+        value_enum,
+        default_value = "dynamic",
+        help = "how to normalise track volume"
+    )]
+    pub normalisation_method: NormalisationMethod,
+    #[clap(
+        long,
+        env,
+        default_value_t = 0.0,
+        allow_negative_numbers = true,
+        help = "pregain in dB applied by volume normalisation"
+    )]
+    pub normalisation_pregain: f64,
+    #[clap(
+        long,
+        env,
+        default_value_t = true,
+        action = ArgAction::Set,
+        help = "play tracks back to back without gaps"
+    )]
+    pub gapless: bool,
+}
+
+impl AudioOptions {
+    pub fn player_config(&self) -> PlayerConfig {
+        PlayerConfig {
+            bitrate: match self.bitrate {
+                Bitrate::Kbps96 => spotify_config::Bitrate::Bitrate96,
+                Bitrate::Kbps160 => spotify_config::Bitrate::Bitrate160,
+                Bitrate::Kbps320 => spotify_config::Bitrate::Bitrate320,
+            },
+            normalisation: self.normalisation,
+            normalisation_method: match self.normalisation_method {
+                NormalisationMethod::Basic => spotify_config::NormalisationMethod::Basic,
+                NormalisationMethod::Dynamic => spotify_config::NormalisationMethod::Dynamic,
+            },
+            normalisation_pregain_db: self.normalisation_pregain,
+            gapless: self.gapless,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use librespot::{
+    core::{
+        session::Session,
+        spotify_id::{SpotifyAudioType, SpotifyId},
+    },
+    metadata::{Artist, Episode, Metadata, Show, Track},
+    playback::player::{PlayerEvent as SpotifyPlayerEvent, PlayerEventChannel},
+};
+use tokio::sync::{broadcast, watch};
+
+// how far the position may drift from where we expect it before we call it a seek rather than the player catching
+// up after a stall
+const SEEK_TOLERANCE: Duration = Duration::from_secs(2);
+
+// what's playing, for people rather than for spirc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackInfo {
+    // base62
+    pub id: String,
+    pub uri: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub duration: Duration,
+}
+
+impl TrackInfo {
+    // link to the track (or episode) on open.spotify.com
+    pub fn url(&self) -> String {
+        let path = self
+            .uri
+            .strip_prefix("spotify:")
+            .unwrap_or(&self.uri)
+            .replace(':', "/");
+        format!("https://open.spotify.com/{path}")
+    }
+}
+
+// the player's events, boiled down to the ones worth telling anybody about. positions are into the current track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayerEvent {
+    TrackChanged {
+        track: TrackInfo,
+        position: Duration,
+    },
+    Paused {
+        track_id: String,
+        position: Duration,
+    },
+    Resumed {
+        track_id: String,
+        position: Duration,
+    },
+    Seeked {
+        track_id: String,
+        position: Duration,
+    },
+    Ended {
+        track_id: String,
+    },
+}
+
+// what's playing right now, for when you'd rather ask than listen for events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NowPlaying {
+    pub track: TrackInfo,
+    pub paused: bool,
+    position: Duration,
+    // when the player told us the position
+    at: Instant,
+}
+
+impl NowPlaying {
+    pub fn position(&self) -> Duration {
+        if self.paused {
+            self.position
+        } else {
+            (self.position + self.at.elapsed()).min(self.track.duration)
+        }
+    }
+
+    fn apply(now_playing: &mut Option<Self>, event: &PlayerEvent, at: Instant) {
+        let (paused, position) = match event {
+            PlayerEvent::TrackChanged { track, position } => {
+                *now_playing = Some(Self {
+                    track: track.clone(),
+                    paused: false,
+                    position: *position,
+                    at,
+                });
+                return;
+            }
+            PlayerEvent::Ended { .. } => {
+                *now_playing = None;
+                return;
+            }
+            PlayerEvent::Paused { position, .. } => (Some(true), position),
+            PlayerEvent::Resumed { position, .. } => (Some(false), position),
+            PlayerEvent::Seeked { position, .. } => (None, position),
+        };
+        if let Some(now_playing) = now_playing {
+            now_playing.paused = paused.unwrap_or(now_playing.paused);
+            now_playing.position = *position;
+            now_playing.at = at;
+        }
+    }
+}
+
+// turns librespot's player events into ours until the player goes away
+pub(crate) async fn forward_events(
+    session: Session,
+    mut rx: PlayerEventChannel,
+    tx: broadcast::Sender<PlayerEvent>,
+    now_playing: watch::Sender<Option<NowPlaying>>,
+) {
+    let mut tracker = Tracker::default();
+    while let Some(event) = rx.recv().await {
+        // fetching metadata takes a moment, so note when this actually happened
+        let at = Instant::now();
+        for transition in tracker.handle(&event, at) {
+            let event = match transition {
+                Transition::TrackChanged {
+                    id,
+                    position,
+                    duration,
+                } => PlayerEvent::TrackChanged {
+                    track: track_info(&session, id, duration).await,
+                    position,
+                },
+                Transition::Paused { id, position } => PlayerEvent::Paused {
+                    track_id: base62(id),
+                    position,
+                },
+                Transition::Resumed { id, position } => PlayerEvent::Resumed {
+                    track_id: base62(id),
+                    position,
+                },
+                Transition::Seeked { id, position } => PlayerEvent::Seeked {
+                    track_id: base62(id),
+                    position,
+                },
+                Transition::Ended { id } => PlayerEvent::Ended {
+                    track_id: base62(id),
+                },
+            };
+            tracing::debug!(?event, "player event");
+            now_playing.send_modify(|now_playing| NowPlaying::apply(now_playing, &event, at));
+            // nobody listening is fine
+            let _ = tx.send(event);
+        }
+    }
+    tracing::debug!("player event channel closed");
+}
+
+async fn track_info(session: &Session, id: SpotifyId, duration: Duration) -> TrackInfo {
+    let (title, artists, duration) = match fetch_metadata(session, id).await {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            tracing::warn!(?id, ?err, "failed to fetch track metadata");
+            ("Unknown track".to_string(), Vec::new(), duration)
+        }
+    };
+    TrackInfo {
+        id: base62(id),
+        uri: id.to_uri().unwrap_or_default(),
+        title,
+        artists,
+        duration,
+    }
+}
+
+// title, artists (or the show, for podcasts) and duration
+async fn fetch_metadata(
+    session: &Session,
+    id: SpotifyId,
+) -> Result<(String, Vec<String>, Duration)> {
+    match id.audio_type {
+        SpotifyAudioType::Track => {
+            let track = Track::get(session, id)
+                .await
+                .map_err(|_| anyhow!("failed to fetch track"))?;
+            let mut artists = Vec::with_capacity(track.artists.len());
+            for artist_id in track.artists {
+                let artist = Artist::get(session, artist_id)
+                    .await
+                    .map_err(|_| anyhow!("failed to fetch artist"))?;
+                artists.push(artist.name);
+            }
+            Ok((track.name, artists, millis(track.duration)))
+        }
+        SpotifyAudioType::Podcast => {
+            let episode = Episode::get(session, id)
+                .await
+                .map_err(|_| anyhow!("failed to fetch episode"))?;
+            let show = Show::get(session, episode.show)
+                .await
+                .map_err(|_| anyhow!("failed to fetch show"))?;
+            Ok((episode.name, vec![show.name], millis(episode.duration)))
+        }
+        SpotifyAudioType::NonPlayable => bail!("not playable"),
+    }
+}
+
+fn base62(id: SpotifyId) -> String {
+    id.to_base62().unwrap_or_default()
+}
+
+fn millis(ms: impl TryInto<u64>) -> Duration {
+    Duration::from_millis(ms.try_into().unwrap_or(0))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Transition {
+    TrackChanged {
+        id: SpotifyId,
+        position: Duration,
+        duration: Duration,
+    },
+    Paused {
+        id: SpotifyId,
+        position: Duration,
+    },
+    Resumed {
+        id: SpotifyId,
+        position: Duration,
+    },
+    Seeked {
+        id: SpotifyId,
+        position: Duration,
+    },
+    Ended {
+        id: SpotifyId,
+    },
+}
+
+// librespot tells us what the player is doing, not what changed, so remember enough to tell the difference
+#[derive(Default)]
+struct Tracker {
+    current: Option<SpotifyId>,
+    paused: bool,
+    position: Duration,
+    // when we learned the position
+    at: Option<Instant>,
+}
+
+impl Tracker {
+    fn handle(&mut self, event: &SpotifyPlayerEvent, now: Instant) -> Vec<Transition> {
+        let mut transitions = Vec::new();
+        match *event {
+            SpotifyPlayerEvent::Playing {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            }
+            | SpotifyPlayerEvent::Paused {
+                track_id,
+                position_ms,
+                duration_ms,
+                ..
+            } => {
+                let paused = matches!(event, SpotifyPlayerEvent::Paused { .. });
+                let position = millis(position_ms);
+                if self.current != Some(track_id) {
+                    transitions.push(Transition::TrackChanged {
+                        id: track_id,
+                        position,
+                        duration: millis(duration_ms),
+                    });
+                    if paused {
+                        transitions.push(Transition::Paused {
+                            id: track_id,
+                            position,
+                        });
+                    }
+                } else if paused != self.paused {
+                    transitions.push(if paused {
+                        Transition::Paused {
+                            id: track_id,
+                            position,
+                        }
+                    } else {
+                        Transition::Resumed {
+                            id: track_id,
+                            position,
+                        }
+                    });
+                } else if self.position_is_off(position, now) {
+                    transitions.push(Transition::Seeked {
+                        id: track_id,
+                        position,
+                    });
+                }
+                self.current = Some(track_id);
+                self.paused = paused;
+                self.position = position;
+                self.at = Some(now);
+            }
+            SpotifyPlayerEvent::EndOfTrack { track_id, .. }
+            | SpotifyPlayerEvent::Stopped { track_id, .. }
+                if self.current == Some(track_id) =>
+            {
+                transitions.push(Transition::Ended { id: track_id });
+                self.current = None;
+                self.at = None;
+            }
+            _ => {}
+        }
+        transitions
+    }
+
+    fn position_is_off(&self, position: Duration, now: Instant) -> bool {
+        let Some(at) = self.at else {
+            return true;
+        };
+        let expected = if self.paused {
+            self.position
+        } else {
+            self.position + now.duration_since(at)
+        };
+        position.abs_diff(expected) > SEEK_TOLERANCE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing(id: SpotifyId, position_ms: u32) -> SpotifyPlayerEvent {
+        SpotifyPlayerEvent::Playing {
+            play_request_id: 0,
+            track_id: id,
+            position_ms,
+            duration_ms: 180_000,
+        }
+    }
+
+    fn paused(id: SpotifyId, position_ms: u32) -> SpotifyPlayerEvent {
+        SpotifyPlayerEvent::Paused {
+            play_request_id: 0,
+            track_id: id,
+            position_ms,
+            duration_ms: 180_000,
+        }
+    }
+
+    #[test]
+    fn test_tracker() {
+        let a = SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let b = SpotifyId::from_base62("7GhIk7Il098yCjg4BQjzvb").unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut tracker = Tracker::default();
+
+        assert_eq!(
+            tracker.handle(&playing(a, 0), at(0)),
+            vec![Transition::TrackChanged {
+                id: a,
+                position: Duration::ZERO,
+                duration: Duration::from_secs(180)
+            }]
+        );
+        // catching up after a stall isn't a seek
+        assert_eq!(tracker.handle(&playing(a, 10_500), at(10)), vec![]);
+        assert_eq!(
+            tracker.handle(&paused(a, 20_000), at(20)),
+            vec![Transition::Paused {
+                id: a,
+                position: Duration::from_secs(20)
+            }]
+        );
+        // seeking while paused
+        assert_eq!(
+            tracker.handle(&paused(a, 60_000), at(30)),
+            vec![Transition::Seeked {
+                id: a,
+                position: Duration::from_secs(60)
+            }]
+        );
+        assert_eq!(
+            tracker.handle(&playing(a, 60_000), at(40)),
+            vec![Transition::Resumed {
+                id: a,
+                position: Duration::from_secs(60)
+            }]
+        );
+        assert_eq!(
+            tracker.handle(&playing(a, 10_000), at(45)),
+            vec![Transition::Seeked {
+                id: a,
+                position: Duration::from_secs(10)
+            }]
+        );
+        assert_eq!(
+            tracker.handle(
+                &SpotifyPlayerEvent::EndOfTrack {
+                    play_request_id: 0,
+                    track_id: a
+                },
+                at(215)
+            ),
+            vec![Transition::Ended { id: a }]
+        );
+        // loaded paused, eg when the user picks the device without hitting play
+        assert_eq!(
+            tracker.handle(&paused(b, 0), at(216)),
+            vec![
+                Transition::TrackChanged {
+                    id: b,
+                    position: Duration::ZERO,
+                    duration: Duration::from_secs(180)
+                },
+                Transition::Paused {
+                    id: b,
+                    position: Duration::ZERO
+                }
+            ]
+        );
+    }
+}
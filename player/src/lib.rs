@@ -0,0 +1,405 @@
+pub mod config;
+pub mod events;
+
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use librespot::{
+    connect::spirc::Spirc,
+    core::{
+        config::{ConnectConfig, SessionConfig},
+        session::Session,
+    },
+    playback::{
+        audio_backend::{Sink, SinkResult},
+        config::VolumeCtrl,
+        convert::Converter,
+        decoder::AudioPacket,
+        mixer::{self, Mixer, MixerConfig, VolumeGetter},
+        player::Player as SpotifyPlayer,
+    },
+    protocol::spirc::{Frame, MessageType},
+};
+use protobuf::Message as _;
+use sha1::{Digest, Sha1};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+
+pub use crate::config::AudioOptions;
+pub use crate::events::{NowPlaying, PlayerEvent, TrackInfo};
+pub use librespot::core::authentication::Credentials;
+
+// how many packets the player may get ahead of whoever is reading the pcm
+const PCM_CHANNEL_CAPACITY: usize = 16;
+// how long spirc gets to say goodbye to spotify before we abort it
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+// spirc only lets us nudge the volume by this much at a time. see librespot's VOLUME_STEP_SIZE
+const VOLUME_STEP: u16 = 1024;
+// how many player events a slow subscriber may fall behind by before it misses some
+const EVENT_CHANNEL_CAPACITY: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct PlayerOptions {
+    pub device_name: String,
+    pub audio: AudioOptions,
+}
+
+// transport controls, as if pressed in a spotify client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Pause,
+    Resume,
+    Next,
+    Previous,
+    Seek(Duration),
+}
+
+// a running spotify connect device. audio comes out of the PcmReader returned by `connect`
+pub struct SpotifySession {
+    opts: PlayerOptions,
+    reusable_creds: Credentials,
+    session: Session,
+    device_id: String,
+    spirc: Spirc,
+    volume: Arc<AtomicU16>,
+    events: broadcast::Sender<PlayerEvent>,
+    now_playing: watch::Receiver<Option<NowPlaying>>,
+    // None once it has finished
+    spirc_task: Option<JoinHandle<()>>,
+}
+
+impl SpotifySession {
+    pub async fn connect(opts: PlayerOptions, creds: Credentials) -> Result<(Self, PcmReader)> {
+        let mixer_config = MixerConfig {
+            volume_ctrl: VolumeCtrl::Log(VolumeCtrl::DEFAULT_DB_RANGE),
+            ..Default::default()
+        };
+
+        let connect_config = ConnectConfig {
+            name: opts.device_name.clone(),
+            initial_volume: None,
+            has_volume_ctrl: true,
+            autoplay: true,
+            device_type: Default::default(),
+        };
+
+        let device_id = device_id(&connect_config.name);
+        let session_config = SessionConfig {
+            device_id: device_id.clone(),
+            ..Default::default()
+        };
+
+        let player_config = opts.audio.player_config();
+        tracing::debug!(audio = ?opts.audio, "configured audio");
+
+        tracing::debug!("connecting to spotify...");
+
+        let (session, reusable_creds) =
+            Session::connect(session_config, creds, None, false).await?;
+
+        let mixer = TrackedMixer::open(mixer_config);
+        let volume = Arc::clone(&mixer.volume);
+        let soft_volume = mixer.get_soft_volume();
+
+        let (pcm_tx, pcm_rx) = mpsc::channel(PCM_CHANNEL_CAPACITY);
+        let (player, player_events) =
+            SpotifyPlayer::new(player_config, session.clone(), soft_volume, move || {
+                Box::new(ChannelSink { tx: pcm_tx })
+            });
+
+        // ends by itself once the player is gone
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let (now_playing_tx, now_playing) = watch::channel(None);
+        tokio::spawn(events::forward_events(
+            session.clone(),
+            player_events,
+            events.clone(),
+            now_playing_tx,
+        ));
+
+        let (spirc, spirc_task) =
+            Spirc::new(connect_config, session.clone(), player, Box::new(mixer));
+        let spirc_task = Some(tokio::spawn(spirc_task));
+
+        tracing::debug!("connected!");
+
+        Ok((
+            Self {
+                opts,
+                reusable_creds,
+                session,
+                device_id,
+                spirc,
+                volume,
+                events,
+                now_playing,
+                spirc_task,
+            },
+            PcmReader::new(pcm_rx),
+        ))
+    }
+
+    pub fn options(&self) -> &PlayerOptions {
+        &self.opts
+    }
+
+    // creds that can be used to connect a new session for the same account, eg to restart this one
+    pub fn reusable_creds(&self) -> &Credentials {
+        &self.reusable_creds
+    }
+
+    // the volume as shown on the slider in the user's spotify client, 0-100
+    pub fn volume_percent(&self) -> u8 {
+        volume_to_percent(self.volume.load(Ordering::Relaxed))
+    }
+
+    // moves the volume towards the given percentage through spirc, so spotify clients see the change too. returns the
+    // percentage it will end up at, which may be slightly off since spirc only moves in steps.
+    pub fn set_volume_percent(&self, percent: u8) -> u8 {
+        let current = self.volume.load(Ordering::Relaxed);
+        let target = (percent.min(100) as u32 * u16::MAX as u32 / 100) as u16;
+        let steps = ((target as f64 - current as f64) / VOLUME_STEP as f64).round() as i64;
+        for _ in 0..steps.abs() {
+            if steps > 0 {
+                self.spirc.volume_up();
+            } else {
+                self.spirc.volume_down();
+            }
+        }
+        let expected = (current as i64 + steps * VOLUME_STEP as i64).clamp(0, u16::MAX as i64);
+        volume_to_percent(expected as u16)
+    }
+
+    pub fn control(&self, control: Control) {
+        match control {
+            Control::Pause => self.spirc.pause(),
+            Control::Resume => self.spirc.play(),
+            Control::Next => self.spirc.next(),
+            Control::Previous => self.spirc.prev(),
+            Control::Seek(position) => self.seek(position),
+        }
+    }
+
+    // spirc has no seek of its own, so ask for one the way a spotify client would: with a seek frame addressed to
+    // this device. that way spirc's state, and the position other clients show, stays right.
+    fn seek(&self, position: Duration) {
+        let mut frame = Frame::new();
+        frame.set_version(1);
+        frame.set_protocol_version("2.0.0".into());
+        // spirc ignores frames from itself
+        frame.set_ident(format!("{}-control", self.device_id));
+        frame.set_typ(MessageType::kMessageTypeSeek);
+        frame.mut_recipient().push(self.device_id.clone());
+        frame.set_position(position.as_millis().min(u32::MAX as u128) as u32);
+
+        let payload = match frame.write_to_bytes() {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::warn!(?err, "failed to encode seek frame");
+                return;
+            }
+        };
+        // the same uri spirc listens on
+        let uri = format!(
+            "hm://remote/user/{}/",
+            form_urlencoded::byte_serialize(self.session.username().as_bytes()).collect::<String>()
+        );
+        let send = self.session.mercury().send(uri, payload);
+        tokio::spawn(async move {
+            if send.await.is_err() {
+                tracing::warn!("failed to send seek frame");
+            }
+        });
+    }
+
+    // what the player is up to, separately from the audio. only sees events from after subscribing.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<PlayerEvent> {
+        self.events.subscribe()
+    }
+
+    // None until spotify starts playing something here, and after it finishes
+    pub fn now_playing(&self) -> Option<NowPlaying> {
+        self.now_playing.borrow().clone()
+    }
+
+    // resolves when the device stops on its own, eg if spotify drops the session
+    pub async fn stopped(&mut self) {
+        if let Some(spirc_task) = self.spirc_task.as_mut() {
+            let _ = spirc_task.await;
+            self.spirc_task = None;
+        }
+    }
+
+    // asks spirc to shut down, and aborts it if it doesn't within the timeout. either way, once this returns the
+    // player is gone and the PcmReader is at eof.
+    pub async fn shutdown(mut self) -> Result<Shutdown> {
+        self.spirc.shutdown();
+        let Some(spirc_task) = self.spirc_task.take() else {
+            return Ok(Shutdown::Graceful);
+        };
+        let res = shutdown_task(spirc_task, SHUTDOWN_TIMEOUT).await;
+        tracing::debug!(?res, "spirc task finished");
+        res
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    Graceful,
+    // it didn't stop in time and had to be aborted
+    Forced,
+}
+
+async fn shutdown_task(mut task: JoinHandle<()>, timeout: Duration) -> Result<Shutdown> {
+    match tokio::time::timeout(timeout, &mut task).await {
+        Ok(res) => {
+            res?;
+            Ok(Shutdown::Graceful)
+        }
+        Err(_) => {
+            tracing::warn!("task didn't shut down in time, aborting");
+            task.abort();
+            match task.await {
+                Err(err) if !err.is_cancelled() => Err(err.into()),
+                _ => Ok(Shutdown::Forced),
+            }
+        }
+    }
+}
+
+impl Drop for SpotifySession {
+    fn drop(&mut self) {
+        // no-op if we've already shut down
+        self.spirc.shutdown();
+    }
+}
+
+fn volume_to_percent(volume: u16) -> u8 {
+    ((volume as u32 * 100 + u16::MAX as u32 / 2) / u16::MAX as u32) as u8
+}
+
+// the softvol mixer, but remembers the volume spirc last set so we can report it and step towards a new one
+struct TrackedMixer {
+    inner: Box<dyn Mixer>,
+    volume: Arc<AtomicU16>,
+}
+
+impl TrackedMixer {
+    fn new(inner: Box<dyn Mixer>) -> Self {
+        let volume = Arc::new(AtomicU16::new(inner.volume()));
+        Self { inner, volume }
+    }
+}
+
+impl Mixer for TrackedMixer {
+    fn open(config: MixerConfig) -> Self {
+        Self::new((mixer::find(None).unwrap())(config))
+    }
+
+    fn set_volume(&self, volume: u16) {
+        self.volume.store(volume, Ordering::Relaxed);
+        self.inner.set_volume(volume);
+    }
+
+    fn volume(&self) -> u16 {
+        self.inner.volume()
+    }
+
+    fn get_soft_volume(&self) -> Box<dyn VolumeGetter + Send> {
+        self.inner.get_soft_volume()
+    }
+}
+
+// hands decoded audio to the PcmReader instead of writing it to a pipe. the player calls this from its own thread.
+struct ChannelSink {
+    tx: mpsc::Sender<Vec<i16>>,
+}
+
+impl Sink for ChannelSink {
+    fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        if let AudioPacket::Samples(samples) = packet {
+            // the player exit(1)s the whole process on sink errors, so if nobody is listening anymore just drop
+            // the audio. the session gets shut down by its owner.
+            if self
+                .tx
+                .blocking_send(converter.f64_to_s16(&samples))
+                .is_err()
+            {
+                tracing::trace!("pcm reader went away, dropping audio");
+            }
+        }
+        Ok(())
+    }
+}
+
+// interleaved s16le stereo pcm at 44.1khz. reads block until the player produces audio, and hit eof once the
+// session is shut down.
+pub struct PcmReader {
+    rx: mpsc::Receiver<Vec<i16>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl PcmReader {
+    fn new(rx: mpsc::Receiver<Vec<i16>>) -> Self {
+        Self {
+            rx,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for PcmReader {
+    // must not be called from within an async context
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.rx.blocking_recv() {
+                Some(samples) => {
+                    self.buf.clear();
+                    self.buf
+                        .extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+pub fn device_id(name: &str) -> String {
+    hex::encode(Sha1::digest(name.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_task() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let polite = tokio::spawn(async move {
+            let _ = rx.await;
+        });
+        tx.send(()).unwrap();
+        let res = shutdown_task(polite, Duration::from_millis(100)).await;
+        assert_eq!(res.unwrap(), Shutdown::Graceful);
+
+        // a task that ignores shutdown gets aborted, and whatever it owns is dropped
+        let (alive_tx, mut alive_rx) = tokio::sync::mpsc::channel::<()>(1);
+        let stubborn = tokio::spawn(async move {
+            let _alive = alive_tx;
+            std::future::pending::<()>().await
+        });
+        let res = shutdown_task(stubborn, Duration::from_millis(100)).await;
+        assert_eq!(res.unwrap(), Shutdown::Forced);
+        assert!(alive_rx.recv().await.is_none());
+    }
+}
@@ -1,10 +1,22 @@
 use librespot_core::authentication::Credentials;
 
+pub mod sealed;
+pub mod version;
+pub use sealed::{CredsKey, PublicKeyResponse, SealedCreds};
+pub use version::{Capability, Envelope, VersionInfo, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct ForwardCreds {
     pub device_name: String,
     pub key: String,
-    pub creds: Credentials,
+    #[serde(flatten)]
+    pub creds: ForwardedCreds,
+    // only this discord user may claim the key. anyone who has the key may, if unset
+    #[serde(default)]
+    pub discord_user: Option<u64>,
+    // from /link. the receiver binds the key to whoever linked the forwarder, in place of discord_user
+    #[serde(default)]
+    pub link_token: Option<String>,
 }
 
 // custom implementation to not show actual creds in logs
@@ -13,7 +25,349 @@ impl std::fmt::Debug for ForwardCreds {
         f.debug_struct("ForwardCreds")
             .field("device_name", &self.device_name)
             .field("key", &self.key)
-            .field("creds", &self.creds.username)
+            .field("discord_user", &self.discord_user)
+            .field("linked", &self.link_token.is_some())
+            .field("creds", &self.creds)
             .finish()
     }
 }
+
+// sent as either "creds" or "sealed_creds", so forwarders from before sealing still work
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub enum ForwardedCreds {
+    #[serde(rename = "creds")]
+    Plain(Credentials),
+    #[serde(rename = "sealed_creds")]
+    Sealed(SealedCreds),
+}
+
+impl std::fmt::Debug for ForwardedCreds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardedCreds::Plain(creds) => f.debug_tuple("Plain").field(&creds.username).finish(),
+            ForwardedCreds::Sealed(sealed) => f.debug_tuple("Sealed").field(sealed).finish(),
+        }
+    }
+}
+
+// keys get typed into discord, so keep them short and plain: lowercase letters, digits and dashes
+pub const KEY_MIN_LEN: usize = 4;
+pub const KEY_MAX_LEN: usize = 64;
+
+// why a key isn't acceptable, as a message for the user
+pub fn validate_key(key: &str) -> Result<(), String> {
+    if key.len() < KEY_MIN_LEN || key.len() > KEY_MAX_LEN {
+        return Err(format!(
+            "keys must be {}-{} characters long",
+            KEY_MIN_LEN, KEY_MAX_LEN
+        ));
+    }
+    if !key
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    {
+        return Err("keys may only contain lowercase letters, digits and dashes".to_string());
+    }
+    if key.starts_with('-') || key.ends_with('-') {
+        return Err("keys can't start or end with a dash".to_string());
+    }
+    Ok(())
+}
+
+// what went wrong with an api request, in the body of every error response
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    // for people
+    pub message: String,
+    // when it's worth trying again, for rate and capacity limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // the body didn't parse, or isn't acceptable
+    InvalidRequest,
+    InvalidKey,
+    IncompatibleVersion,
+    // someone else's creds are waiting on the key
+    KeyInUse,
+    // no api token
+    Unauthorized,
+    // an api token we don't know
+    Forbidden,
+    RateLimited,
+    // too many keys waiting to be claimed
+    AtCapacity,
+    Internal,
+    // from a newer receiver
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct ForwardCredsResponse {
+    // how long the key stays valid if nobody claims it
+    pub expires_in_secs: u64,
+    // who the key was bound to through the link token, if it was valid
+    #[serde(default)]
+    pub linked_user: Option<u64>,
+    // proves the key's session socket is ours, sent in SESSION_SECRET_HEADER
+    #[serde(default)]
+    pub session_secret: Option<String>,
+}
+
+// not the secret, it'd end up in logs
+impl std::fmt::Debug for ForwardCredsResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ForwardCredsResponse")
+            .field("expires_in_secs", &self.expires_in_secs)
+            .field("linked_user", &self.linked_user)
+            .finish()
+    }
+}
+
+// POST /api/link, to start pairing a forwarder with a discord user
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct LinkRequest {
+    pub device_name: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct LinkResponse {
+    // for the user to type into /link
+    pub code: String,
+    // the forwarder's secret from then on, once the code is confirmed
+    pub token: String,
+    pub expires_in_secs: u64,
+}
+
+impl std::fmt::Debug for LinkResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LinkResponse")
+            .field("code", &self.code)
+            .field("expires_in_secs", &self.expires_in_secs)
+            .finish()
+    }
+}
+
+// POST /api/link/status. the token goes in the body so it stays out of access logs
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct LinkStatusRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum LinkStatus {
+    // waiting for someone to /link the code
+    Pending { expires_in_secs: u64 },
+    Linked { discord_user: u64 },
+    // the code expired, or the link was removed with /unlink
+    Unknown,
+}
+
+// one guild's playback, as listed by GET /api/sessions. not the key, which would let any api client take the stream
+// over
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct SessionInfo {
+    pub guild_id: u64,
+    pub device_name: String,
+    // discord user id of whoever ran /play_spotify
+    pub started_by: u64,
+    // unix timestamp
+    pub started_at: u64,
+    pub track: Option<TrackSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct TrackSummary {
+    pub id: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct SessionsResponse {
+    pub max_sessions: usize,
+    pub sessions: Vec<SessionInfo>,
+}
+
+// where a key was claimed
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct VoiceLocation {
+    pub guild_id: u64,
+    pub guild_name: String,
+    pub channel_id: u64,
+    pub channel_name: String,
+}
+
+// what's become of a key, from GET /api/key/:key/status
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum KeyStatus {
+    // waiting for someone to /play_spotify it
+    Pending {
+        expires_in_secs: u64,
+    },
+    // someone did, and the bot is joining or has joined their voice channel, but spotify isn't playing anything yet
+    Claimed {
+        location: VoiceLocation,
+    },
+    Playing {
+        location: VoiceLocation,
+        track: TrackSummary,
+    },
+    // nobody claimed it in time
+    Expired,
+    // it was played and has since been stopped
+    Stopped,
+    // never seen, or too long ago to remember
+    Unknown,
+}
+
+// forwarders keep a websocket open at /api/session/:key while their device is alive, and send a ping this often
+pub const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+// if the receiver hears nothing for this long it considers the device gone
+pub const HEARTBEAT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(20);
+// opening the session socket takes the secret from ForwardCredsResponse in this header
+pub const SESSION_SECRET_HEADER: &str = "x-session-secret";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_key() {
+        for key in [
+            "bird42",
+            "otter-maple-comet",
+            "abcd",
+            &"a".repeat(KEY_MAX_LEN),
+        ] {
+            assert!(validate_key(key).is_ok(), "{key:?}");
+        }
+        for key in [
+            "abc",
+            &"a".repeat(KEY_MAX_LEN + 1),
+            "Bird42",
+            "bird 42",
+            "bird:42",
+            "-bird",
+            "bird-",
+            "bírd",
+        ] {
+            assert!(validate_key(key).is_err(), "{key:?}");
+        }
+    }
+
+    // what goes over the wire. changing any of these means bumping PROTOCOL_VERSION
+    #[test]
+    fn test_wire_format() {
+        use serde_json::json;
+
+        let req = Envelope::new(
+            "0.1.0",
+            ForwardCreds {
+                device_name: "danube".to_string(),
+                key: "otter-maple-comet".to_string(),
+                creds: ForwardedCreds::Sealed(SealedCreds {
+                    sealed: "c2VhbGVk".to_string(),
+                }),
+                discord_user: Some(1234),
+                link_token: None,
+            },
+        );
+        let value = json!({
+            "protocol_version": PROTOCOL_VERSION,
+            "forwarder_version": "0.1.0",
+            "capabilities": ["sealed_creds", "key_status", "discord_user", "link"],
+            "device_name": "danube",
+            "key": "otter-maple-comet",
+            "sealed_creds": {"sealed": "c2VhbGVk"},
+            "discord_user": 1234,
+            "link_token": null,
+        });
+        assert_eq!(serde_json::to_value(&req).unwrap(), value);
+        let parsed: Envelope<ForwardCreds> = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.protocol_version, PROTOCOL_VERSION);
+        assert!(matches!(parsed.body.creds, ForwardedCreds::Sealed(_)));
+
+        // from a forwarder that predates versioning, sealing, and binding keys to users
+        let legacy: Envelope<ForwardCreds> = serde_json::from_value(json!({
+            "device_name": "danube",
+            "key": "bird42",
+            "creds": {"username": "user", "auth_type": 0, "auth_data": "cGFzcw=="},
+        }))
+        .unwrap();
+        assert_eq!(legacy.protocol_version, 0);
+        assert_eq!(legacy.forwarder_version, None);
+        assert_eq!(legacy.body.discord_user, None);
+        let ForwardedCreds::Plain(creds) = legacy.body.creds else {
+            panic!("expected plain creds");
+        };
+        assert_eq!(creds.username, "user");
+        assert_eq!(creds.auth_data, b"pass");
+
+        let info = VersionInfo {
+            protocol_version: 1,
+            min_protocol_version: 0,
+            receiver_version: "0.1.0".to_string(),
+            capabilities: vec![Capability::SealedCreds, Capability::Link],
+        };
+        let value = json!({
+            "protocol_version": 1,
+            "min_protocol_version": 0,
+            "receiver_version": "0.1.0",
+            "capabilities": ["sealed_creds", "link"],
+        });
+        assert_eq!(serde_json::to_value(&info).unwrap(), value);
+        assert_eq!(serde_json::from_value::<VersionInfo>(value).unwrap(), info);
+        // from a newer receiver
+        let newer: VersionInfo = serde_json::from_value(json!({
+            "protocol_version": 3,
+            "min_protocol_version": 2,
+            "receiver_version": "9.0.0",
+            "capabilities": ["link", "teleport"],
+        }))
+        .unwrap();
+        assert_eq!(
+            newer.capabilities,
+            vec![Capability::Link, Capability::Unknown]
+        );
+        assert!(newer.check_compatible().is_err());
+        assert!(info.check_compatible().is_ok());
+
+        let status = KeyStatus::Pending {
+            expires_in_secs: 30,
+        };
+        let value = json!({"state": "pending", "expires_in_secs": 30});
+        assert_eq!(serde_json::to_value(&status).unwrap(), value);
+        assert_eq!(serde_json::from_value::<KeyStatus>(value).unwrap(), status);
+        assert_eq!(
+            serde_json::to_value(KeyStatus::Stopped).unwrap(),
+            json!({"state": "stopped"})
+        );
+
+        let error = ApiError {
+            code: ErrorCode::RateLimited,
+            message: "slow down".to_string(),
+            retry_after_secs: Some(3),
+        };
+        let value = json!({"code": "rate_limited", "message": "slow down", "retry_after_secs": 3});
+        assert_eq!(serde_json::to_value(&error).unwrap(), value);
+        assert_eq!(serde_json::from_value::<ApiError>(value).unwrap(), error);
+        let newer: ApiError =
+            serde_json::from_value(json!({"code": "on_fire", "message": "help"})).unwrap();
+        assert_eq!(newer.code, ErrorCode::Unknown);
+        assert_eq!(newer.retry_after_secs, None);
+
+        let status = LinkStatus::Linked { discord_user: 1234 };
+        let value = json!({"state": "linked", "discord_user": 1234});
+        assert_eq!(serde_json::to_value(&status).unwrap(), value);
+        assert_eq!(serde_json::from_value::<LinkStatus>(value).unwrap(), status);
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use librespot_core::authentication::Credentials;
+use ring::{aead, hkdf};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const KEY_LEN: usize = 32;
+// ties the derived key to what it's for
+const HKDF_INFO: &[u8] = b"spotify-remote sealed creds v1";
+
+// creds sealed for the receiver's key, so they're unreadable in logs, in transit and while they wait to be
+// claimed. an x25519 exchange between a throwaway key and the receiver's, then chacha20-poly1305, much like a
+// libsodium sealed box. base64 of the throwaway public key followed by the ciphertext
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub struct SealedCreds {
+    pub sealed: String,
+}
+
+impl std::fmt::Debug for SealedCreds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SealedCreds")
+            .field("len", &self.sealed.len())
+            .finish()
+    }
+}
+
+// from GET /api/public_key
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct PublicKeyResponse {
+    // base64
+    pub public_key: String,
+}
+
+// seals creds for the receiver with this public key, as given by /api/public_key
+pub fn seal(creds: &Credentials, public_key: &str) -> Result<SealedCreds> {
+    let public_key: [u8; KEY_LEN] = BASE64
+        .decode(public_key)
+        .context("public key isn't base64")?
+        .try_into()
+        .map_err(|_| anyhow!("public key is the wrong length"))?;
+    seal_for(creds, &PublicKey::from(public_key))
+}
+
+fn seal_for(creds: &Credentials, receiver_public: &PublicKey) -> Result<SealedCreds> {
+    let ephemeral = StaticSecret::from(rand::random::<[u8; KEY_LEN]>());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let key = derive_key(
+        &ephemeral,
+        receiver_public,
+        &ephemeral_public,
+        receiver_public,
+    )?;
+
+    let mut ciphertext = serde_json::to_vec(creds)?;
+    key.seal_in_place_append_tag(nonce(), aead::Aad::empty(), &mut ciphertext)
+        .map_err(|_| anyhow!("failed to seal creds"))?;
+    let mut sealed = ephemeral_public.as_bytes().to_vec();
+    sealed.append(&mut ciphertext);
+    Ok(SealedCreds {
+        sealed: BASE64.encode(sealed),
+    })
+}
+
+// both ends come out with the same key. salted with both public keys, so it's tied to this exchange
+fn derive_key(
+    secret: &StaticSecret,
+    their_public: &PublicKey,
+    ephemeral_public: &PublicKey,
+    receiver_public: &PublicKey,
+) -> Result<aead::LessSafeKey> {
+    let shared = secret.diffie_hellman(their_public);
+    // a low order point was passed off as a public key
+    if !shared.was_contributory() {
+        return Err(anyhow!("bad public key"));
+    }
+    let mut salt = ephemeral_public.as_bytes().to_vec();
+    salt.extend_from_slice(receiver_public.as_bytes());
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(shared.as_bytes());
+    let okm = prk
+        .expand(&[HKDF_INFO], &aead::CHACHA20_POLY1305)
+        .map_err(|_| anyhow!("failed to derive key"))?;
+    Ok(aead::LessSafeKey::new(aead::UnboundKey::from(okm)))
+}
+
+// every key is only ever used once, so a fixed nonce is fine
+fn nonce() -> aead::Nonce {
+    aead::Nonce::assume_unique_for_key([0; aead::NONCE_LEN])
+}
+
+// the receiver's half. made fresh on every start, since pending creds don't outlive the process anyway
+pub struct CredsKey {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl CredsKey {
+    pub fn generate() -> Self {
+        let secret = StaticSecret::from(rand::random::<[u8; KEY_LEN]>());
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    // base64, for /api/public_key
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.public.as_bytes())
+    }
+
+    // for creds that arrived in the clear, so they're kept the same way as the rest
+    pub fn seal(&self, creds: &Credentials) -> Result<SealedCreds> {
+        seal_for(creds, &self.public)
+    }
+
+    pub fn open(&self, sealed: &SealedCreds) -> Result<Credentials> {
+        let sealed = BASE64
+            .decode(&sealed.sealed)
+            .context("sealed creds aren't base64")?;
+        if sealed.len() < KEY_LEN {
+            return Err(anyhow!("sealed creds are too short"));
+        }
+        let (ephemeral_public, ciphertext) = sealed.split_at(KEY_LEN);
+        let ephemeral_public = PublicKey::from(<[u8; KEY_LEN]>::try_from(ephemeral_public)?);
+        let key = derive_key(
+            &self.secret,
+            &ephemeral_public,
+            &ephemeral_public,
+            &self.public,
+        )?;
+        let mut ciphertext = ciphertext.to_vec();
+        // most likely sealed for the key we had before a restart
+        let plaintext = key
+            .open_in_place(nonce(), aead::Aad::empty(), &mut ciphertext)
+            .map_err(|_| anyhow!("failed to open sealed creds"))?;
+        Ok(serde_json::from_slice(plaintext)?)
+    }
+}
+
+impl std::fmt::Debug for CredsKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CredsKey")
+            .field("public_key", &self.public_key())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let key = CredsKey::generate();
+        let creds = Credentials::with_password("user", "hunter2");
+        let sealed = seal(&creds, &key.public_key()).unwrap();
+        assert!(!sealed.sealed.contains("hunter2"));
+
+        let opened = key.open(&sealed).unwrap();
+        assert_eq!(opened.username, "user");
+        assert_eq!(opened.auth_data, creds.auth_data);
+        assert_eq!(
+            key.open(&key.seal(&creds).unwrap()).unwrap().username,
+            "user"
+        );
+
+        // someone else's key, eg ours from before a restart
+        assert!(CredsKey::generate().open(&sealed).is_err());
+        let mut tampered = BASE64.decode(&sealed.sealed).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = SealedCreds {
+            sealed: BASE64.encode(tampered),
+        };
+        assert!(key.open(&tampered).is_err());
+
+        assert!(seal(&creds, "not base64!").is_err());
+        assert!(seal(&creds, &BASE64.encode([0u8; 3])).is_err());
+        // a low order point
+        assert!(seal(&creds, &BASE64.encode([0u8; KEY_LEN])).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+// bumped whenever a change to the api would trip up the other side. forwarders from before versioning don't send
+// one, and count as 0
+pub const PROTOCOL_VERSION: u32 = 1;
+// the oldest forwarders the receiver still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 0;
+
+// optional features, for adapting to the other side without bumping the version. unknown ones are from a newer
+// release, and ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    SealedCreds,
+    KeyStatus,
+    DiscordUser,
+    Link,
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    // everything this build supports
+    pub const ALL: [Capability; 4] = [
+        Capability::SealedCreds,
+        Capability::KeyStatus,
+        Capability::DiscordUser,
+        Capability::Link,
+    ];
+}
+
+// GET /api/version, which forwarders check before anything else
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct VersionInfo {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+    // the receiver's release
+    pub receiver_version: String,
+    pub capabilities: Vec<Capability>,
+}
+
+impl VersionInfo {
+    pub fn supports(&self, protocol_version: u32) -> bool {
+        (self.min_protocol_version..=self.protocol_version).contains(&protocol_version)
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    // why a forwarder speaking our protocol version can't talk to this receiver, as a message for the user
+    pub fn check_compatible(&self) -> Result<(), String> {
+        if self.supports(PROTOCOL_VERSION) {
+            Ok(())
+        } else if self.protocol_version < PROTOCOL_VERSION {
+            Err(format!(
+                "the receiver (version {}, protocol {}) is older than this forwarder (protocol {}), upgrade the receiver or use an older forwarder",
+                self.receiver_version, self.protocol_version, PROTOCOL_VERSION
+            ))
+        } else {
+            Err(format!(
+                "the receiver (version {}) no longer supports this forwarder's protocol {}, it needs at least {}. upgrade the forwarder",
+                self.receiver_version, PROTOCOL_VERSION, self.min_protocol_version
+            ))
+        }
+    }
+}
+
+// wraps what forwarders send, saying who's sending it. the fields sit alongside the body's, so bodies from before
+// versioning still parse
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Envelope<T> {
+    #[serde(default)]
+    pub protocol_version: u32,
+    #[serde(default)]
+    pub forwarder_version: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    #[serde(flatten)]
+    pub body: T,
+}
+
+impl<T> Envelope<T> {
+    // from this build of the forwarder
+    pub fn new(forwarder_version: &str, body: T) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            forwarder_version: Some(forwarder_version.to_string()),
+            capabilities: Capability::ALL.to_vec(),
+            body,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use protocol::{ApiError, ErrorCode};
+
+// an error response from the api: the status, and a body saying what went wrong
+#[derive(Debug)]
+pub struct ApiFailure {
+    status: StatusCode,
+    error: ApiError,
+}
+
+impl ApiFailure {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        let status = match code {
+            ErrorCode::InvalidRequest | ErrorCode::InvalidKey | ErrorCode::IncompatibleVersion => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::KeyInUse => StatusCode::CONFLICT,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::AtCapacity => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal | ErrorCode::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self {
+            status,
+            error: ApiError {
+                code,
+                message: message.into(),
+                retry_after_secs: None,
+            },
+        }
+    }
+
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        // rounded up, so retrying right on time works
+        self.error.retry_after_secs = Some(retry_after.as_secs_f64().ceil().max(1.0) as u64);
+        self
+    }
+}
+
+impl IntoResponse for ApiFailure {
+    fn into_response(self) -> Response {
+        let retry_after = self.error.retry_after_secs;
+        let mut resp = (self.status, Json(self.error)).into_response();
+        if let Some(secs) = retry_after {
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        resp
+    }
+}
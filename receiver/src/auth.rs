@@ -0,0 +1,128 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use protocol::ErrorCode;
+use ring::{constant_time, digest};
+
+use crate::api_error::ApiFailure;
+
+// the set of bearer tokens forwarders may use to talk to the api. empty means auth is disabled
+#[derive(Debug, Default, Clone)]
+pub struct ApiTokens {
+    // digests rather than the tokens, so every comparison takes as long whatever the token
+    tokens: Vec<Vec<u8>>,
+}
+
+impl ApiTokens {
+    // tokens come from the given list plus, if set, a file with one token per line. lines starting with '#' are ignored
+    pub fn load(tokens: &[String], file: Option<&str>) -> Result<Self> {
+        let mut set: HashSet<String> = tokens
+            .iter()
+            .map(|t| t.trim().to_string())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if let Some(path) = file {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read api tokens file {}", path))?;
+            set.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(str::to_string),
+            );
+        }
+        Ok(Self {
+            tokens: set.iter().map(|t| token_digest(t)).collect(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    // checks an Authorization header value. 401 if there's no usable bearer token, 403 if it's not one we know
+    pub fn check(&self, authorization: Option<&str>) -> Result<(), StatusCode> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let token = authorization
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let token = token_digest(token);
+        // checks every token, so how long it takes doesn't say which one matched
+        let known = self.tokens.iter().fold(false, |known, t| {
+            constant_time::verify_slices_are_equal(t, &token).is_ok() | known
+        });
+        if known {
+            Ok(())
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+fn token_digest(token: &str) -> Vec<u8> {
+    digest::digest(&digest::SHA256, token.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+pub async fn require_token<B>(
+    State(tokens): State<Arc<ApiTokens>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiFailure> {
+    let authorization = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok());
+    if let Err(status) = tokens.check(authorization) {
+        tracing::debug!(?status, uri = ?req.uri(), "rejected api request");
+        return Err(if status == StatusCode::UNAUTHORIZED {
+            ApiFailure::new(
+                ErrorCode::Unauthorized,
+                "this receiver requires an api token",
+            )
+        } else {
+            ApiFailure::new(ErrorCode::Forbidden, "unknown api token")
+        });
+    }
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let tokens = ApiTokens::load(&["secret".to_string(), "other".to_string()], None).unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens.check(Some("Bearer secret")), Ok(()));
+        assert_eq!(tokens.check(Some("Bearer other")), Ok(()));
+        assert_eq!(tokens.check(None), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(
+            tokens.check(Some("Basic secret")),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            tokens.check(Some("Bearer nope")),
+            Err(StatusCode::FORBIDDEN)
+        );
+
+        assert_eq!(ApiTokens::default().check(None), Ok(()));
+    }
+}
@@ -1,41 +1,77 @@
-use std::{
-    process::{Command, Stdio},
-    sync::{Arc, RwLock},
-};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use clap::Parser;
 
-use poise::serenity_prelude::GatewayIntents;
-use songbird::input::{children_to_reader, Codec, Container, Input};
+use player::config::Bitrate;
+use player::{AudioOptions, Control, Credentials, PlayerOptions, SpotifySession};
+use poise::serenity_prelude::{
+    self as serenity, ChannelId, GatewayIntents, Guild, GuildId, Interaction,
+    InteractionResponseType, MessageComponentInteraction, RoleId, UserId,
+};
+use protocol::{CredsKey, ForwardedCreds, VoiceLocation};
+use songbird::input::{Codec, Container, Input, Reader};
 use songbird::SerenityInit;
 
-use crate::creds_registry::CredsRegistry;
+use crate::creds_registry::{CredsRegistry, TakeError};
+use crate::forwarder_sessions::{self, ForwarderSessions};
+use crate::links::LinkRegistry;
+use crate::metrics::{self, CountingReader};
+use crate::now_playing;
+use crate::panel::{self, Action};
+use crate::rate_limit::{Lockout, RateLimiter};
+use crate::resampler::Resampler;
+use crate::sessions::{describe_shutdown, PlaybackSession, Reservation, SessionManager};
 
 #[derive(Debug, Parser, Clone)]
 pub struct BotOptions {
-    #[clap(
-        long,
-        env,
-        default_value = "player",
-        help = "path to the player binary"
-    )]
-    player_path: String,
     #[clap(short, long, env = "DISCORD_TOKEN")]
     discord_token: String,
+    // members with this role can use the transport controls on anyone's stream, not just their own
+    #[clap(long, env = "CONTROL_ROLE")]
+    control_role: Option<u64>,
+    // how many commands and button presses a minute each user gets, 0 for no limit
+    #[clap(long, env = "COMMAND_RATE", default_value = "10")]
+    command_rate: u32,
+    // how many unknown keys a user may try with /play_spotify before being locked out, 0 for no lockouts
+    #[clap(long, env = "KEY_ATTEMPTS", default_value = "5")]
+    key_attempts: u32,
+    // how long in seconds the lockout lasts
+    #[clap(long, env = "KEY_LOCKOUT", default_value = "900")]
+    key_lockout: u64,
+    // defaults for every stream, /play_spotify can override the bitrate
+    #[clap(flatten)]
+    audio: AudioOptions,
 }
 
 // User data, which is stored and accessible in all command invocations
 struct Data {
-    bot_options: BotOptions,
+    audio: AudioOptions,
+    control_role: Option<RoleId>,
     creds_registry: Arc<RwLock<CredsRegistry>>,
+    forwarder_sessions: Arc<Mutex<ForwarderSessions>>,
+    sessions: Arc<Mutex<SessionManager>>,
+    links: Arc<Mutex<LinkRegistry>>,
+    // opens the sealed creds waiting in the registry
+    creds_key: Arc<CredsKey>,
+    command_limiter: Mutex<RateLimiter<UserId>>,
+    // so people can't guess their way into someone else's stream
+    key_lockout: Mutex<Lockout<UserId>>,
 }
 type Error = anyhow::Error;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
 // NOTE: Your bot also needs to be invited with the applications.commands scope. For example, in Discord’s invite link generator (discord.com/developers/applications/XXX/oauth2/url-generator), tick the applications.commands box.
 
-pub async fn run_bot(opts: BotOptions, stream_registry: Arc<RwLock<CredsRegistry>>) -> Result<()> {
+pub async fn run_bot(
+    opts: BotOptions,
+    stream_registry: Arc<RwLock<CredsRegistry>>,
+    forwarder_sessions: Arc<Mutex<ForwarderSessions>>,
+    sessions: Arc<Mutex<SessionManager>>,
+    links: Arc<Mutex<LinkRegistry>>,
+    creds_key: Arc<CredsKey>,
+) -> Result<()> {
     // TODO: pare down
     let intents = GatewayIntents::non_privileged()
         | GatewayIntents::MESSAGE_CONTENT
@@ -55,19 +91,55 @@ pub async fn run_bot(opts: BotOptions, stream_registry: Arc<RwLock<CredsRegistry
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![play_spotify(), leave(), stop(), restart()],
+            commands: vec![
+                play_spotify(),
+                link(),
+                unlink(),
+                leave(),
+                stop(),
+                volume(),
+                nowplaying(),
+                pause(),
+                resume(),
+                skip(),
+                previous(),
+                seek(),
+                reset(),
+                list_sessions(),
+                restart(),
+            ],
             on_error: |error| Box::pin(on_error(error)),
+            pre_command: |ctx| {
+                Box::pin(async move {
+                    metrics::COMMANDS
+                        .with_label_values(&[&ctx.command().qualified_name])
+                        .inc();
+                })
+            },
+            command_check: Some(|ctx| Box::pin(command_check(ctx))),
+            event_handler: |ctx, event, _framework, data| Box::pin(event_handler(ctx, event, data)),
             ..Default::default()
         })
         .client_settings(|settings| settings.register_songbird())
         .token(&opts.discord_token)
         .intents(intents)
-        .setup(|ctx, _ready, framework| {
+        .setup(move |ctx, _ready, framework| {
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                metrics::DISCORD_CONNECTED.set(1);
                 Ok(Data {
-                    bot_options: opts,
+                    audio: opts.audio,
+                    control_role: opts.control_role.map(RoleId),
                     creds_registry: stream_registry,
+                    forwarder_sessions,
+                    sessions,
+                    links,
+                    creds_key,
+                    command_limiter: Mutex::new(RateLimiter::new(opts.command_rate)),
+                    key_lockout: Mutex::new(Lockout::new(
+                        opts.key_attempts,
+                        Duration::from_secs(opts.key_lockout),
+                    )),
                 })
             })
         });
@@ -82,6 +154,8 @@ async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
         poise::FrameworkError::Command { error, ctx } => {
             tracing::warn!("Error in command `{}`: {:?}", ctx.command().name, error,);
         }
+        // command_check already told the user
+        poise::FrameworkError::CommandCheckFailed { error: None, .. } => {}
         error => {
             if let Err(e) = poise::builtins::on_error(error).await {
                 tracing::warn!("Error while handling error: {}", e)
@@ -90,8 +164,102 @@ async fn on_error(error: poise::FrameworkError<'_, Data, Error>) {
     }
 }
 
+// the per-user rate limit on commands
+async fn command_check(ctx: Context<'_>) -> Result<bool> {
+    let res = ctx
+        .data()
+        .command_limiter
+        .lock()
+        .unwrap()
+        .check(ctx.author().id, Instant::now());
+    let Err(retry_after) = res else {
+        return Ok(true);
+    };
+    tracing::debug!(user = ?ctx.author().id, ?retry_after, "rate limited command");
+    metrics::RATE_LIMITED.with_label_values(&["command"]).inc();
+    ctx.send(|m| m.content(slow_down_message(retry_after)).ephemeral(true))
+        .await?;
+    Ok(false)
+}
+
+fn slow_down_message(retry_after: Duration) -> String {
+    format!(
+        "Slow down! Try again in {}",
+        now_playing::format_duration(retry_after + Duration::from_secs(1))
+    )
+}
+
+// whether the user is locked out for guessing keys or link codes, telling them if so
+async fn locked_out(ctx: Context<'_>) -> Result<bool> {
+    let locked = ctx
+        .data()
+        .key_lockout
+        .lock()
+        .unwrap()
+        .locked(&ctx.author().id, Instant::now());
+    let Some(remaining) = locked else {
+        return Ok(false);
+    };
+    metrics::RATE_LIMITED
+        .with_label_values(&["key_lockout"])
+        .inc();
+    ctx.send(|m| {
+        m.content(format!(
+            "Too many unknown keys, try again in {}",
+            now_playing::format_duration(remaining + Duration::from_secs(1))
+        ))
+        .ephemeral(true)
+    })
+    .await?;
+    Ok(true)
+}
+
+// counts a bad key or code against the user. what to add to the reply if that locked them out
+fn record_failure(ctx: Context<'_>) -> String {
+    let locked_out = ctx
+        .data()
+        .key_lockout
+        .lock()
+        .unwrap()
+        .fail(ctx.author().id, Instant::now());
+    match locked_out {
+        Some(duration) => {
+            tracing::info!(user = ?ctx.author().id, "locked out after too many unknown keys");
+            format!(
+                ". That's too many unknown keys, try again in {}",
+                now_playing::format_duration(duration)
+            )
+        }
+        None => String::new(),
+    }
+}
+
+#[derive(Debug, poise::ChoiceParameter)]
+enum Quality {
+    #[name = "low (96kbps)"]
+    Low,
+    #[name = "normal (160kbps)"]
+    Normal,
+    #[name = "high (320kbps)"]
+    High,
+}
+
+impl From<Quality> for Bitrate {
+    fn from(quality: Quality) -> Self {
+        match quality {
+            Quality::Low => Bitrate::Kbps96,
+            Quality::Normal => Bitrate::Kbps160,
+            Quality::High => Bitrate::Kbps320,
+        }
+    }
+}
+
 #[poise::command(slash_command)]
-async fn play_spotify(ctx: Context<'_>, #[description = "Stream key"] key: String) -> Result<()> {
+async fn play_spotify(
+    ctx: Context<'_>,
+    #[description = "Stream key, defaults to your latest stream"] key: Option<String>,
+    #[description = "Audio quality, defaults to the server's setting"] quality: Option<Quality>,
+) -> Result<()> {
     let guild = match ctx.guild() {
         None => {
             ctx.say("This command can only be used in a guild").await?;
@@ -100,7 +268,6 @@ async fn play_spotify(ctx: Context<'_>, #[description = "Stream key"] key: Strin
         Some(g) => g,
     };
 
-    let voice_manager = songbird::get(ctx.serenity_context()).await.unwrap().clone();
     let channel_id = guild
         .voice_states
         .get(&ctx.author().id)
@@ -113,76 +280,218 @@ async fn play_spotify(ctx: Context<'_>, #[description = "Stream key"] key: Strin
             return Ok(());
         }
     };
-    let (call_handler_lock, res) = voice_manager.join(guild.id, connect_to).await;
-    res?;
 
+    let Some(reservation) = SessionManager::reserve(&ctx.data().sessions, guild.id) else {
+        ctx.say(full_message(&ctx.data().sessions)).await?;
+        return Ok(());
+    };
+
+    if locked_out(ctx).await? {
+        return Ok(());
+    }
+
+    // streams from a linked forwarder (or one run with --discord-user) are bound to the user, so we can find them
+    let key = match key {
+        Some(key) => key,
+        None => {
+            let latest = ctx
+                .data()
+                .creds_registry
+                .read()
+                .unwrap()
+                .latest_for(ctx.author().id.0)
+                .map(str::to_string);
+            match latest {
+                Some(key) => key,
+                None => {
+                    ctx.say("You have no stream waiting. Pass the key your forwarder printed, or /link your forwarder so its streams find you").await?;
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    let location = voice_location(&guild, connect_to);
     let creds_req = {
         let mut registry = ctx.data().creds_registry.write().unwrap();
-        registry.take(&key)
+        let creds_req = registry.take(&key, ctx.author().id.0);
+        if creds_req.is_ok() {
+            registry.mark_claimed(&key, location.clone());
+        }
+        creds_req
     };
 
     let creds_req = match creds_req {
-        Some(creds) => creds,
-        None => {
-            ctx.say(format!("No stream found for {key}")).await?;
+        Ok(creds) => creds,
+        Err(err) => {
+            let mut text = match err {
+                TakeError::NotFound => format!("No stream found for {key}"),
+                // without naming the owner, so guessing isn't a way to find out whose key it is
+                TakeError::WrongUser { owner } => {
+                    tracing::debug!(?key, ?owner, "someone else's stream");
+                    format!("The stream {key} can only be played by whoever forwarded it")
+                }
+            };
+            // someone else's key counts too, or it'd be a way to check guesses
+            text += &record_failure(ctx);
+            ctx.send(|m| {
+                m.content(text)
+                    .ephemeral(true)
+                    .allowed_mentions(|a| a.empty_parse())
+            })
+            .await?;
             return Ok(());
         }
     };
+    // connecting to spotify can take longer than discord waits for a response
+    ctx.defer().await?;
 
-    let player_path = ctx.data().bot_options.player_path.clone();
-    tracing::debug!(?player_path, "starting player");
-
-    let mut player_command = Command::new(player_path)
-        .stderr(Stdio::inherit())
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .env("TOKIO_CONSOLE_BIND", "0.0.0.0:0") // so multiple streams don't conflict
-        .spawn()?;
-    let mut player_stdin = player_command.stdin.take().unwrap();
-    serde_json::to_writer(&mut player_stdin, &creds_req.creds)?;
-
-    // spotify streams at 44.1khz, we want 48khz, so use gstreamer to resample it.
-    let gstreamer_command = Command::new("gst-launch-1.0")
-        .args([
-            "filesrc",
-            "location=/dev/stdin",
-            "!",
-            "rawaudioparse",
-            "use-sink-caps=false",
-            "format=pcm",
-            "pcm-format=s16le",
-            "sample-rate=44100",
-            "num-channels=2",
-            "!",
-            "audioconvert",
-            "!",
-            "audioresample",
-            "!",
-            "audio/x-raw,",
-            "rate=48000",
-            "!",
-            "filesink",
-            "location=/dev/stdout",
-        ])
-        .stderr(Stdio::inherit())
-        .stdin(player_command.stdout.take().unwrap())
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    tracing::debug!(?key, "started player processes");
-
-    let reader = children_to_reader::<i16>(vec![player_command, gstreamer_command]);
-
-    let input = Input::new(true, reader, Codec::Pcm, Container::Raw, None);
-
-    let mut call_handler = call_handler_lock.lock().await;
-    call_handler.play_source(input);
+    let mut audio = ctx.data().audio.clone();
+    if let Some(quality) = quality {
+        audio.bitrate = quality.into();
+    }
+    let player_opts = PlayerOptions {
+        device_name: creds_req.device_name.clone(),
+        audio,
+    };
+    // only ever in the clear on their way into the player
+    let creds = match creds_req.creds {
+        ForwardedCreds::Sealed(sealed) => ctx.data().creds_key.open(&sealed),
+        ForwardedCreds::Plain(creds) => Ok(creds),
+    };
+    let res = match creds {
+        Ok(creds) => {
+            start_session(
+                ctx,
+                reservation,
+                location,
+                key.clone(),
+                ctx.author().id,
+                player_opts,
+                creds,
+            )
+            .await
+        }
+        Err(err) => {
+            ctx.say("Couldn't read the stream's credentials, reconnect to the device in spotify to send them again").await?;
+            Err(err)
+        }
+    };
+    if res.is_err() {
+        // so the forwarder hears about it
+        ctx.data().sessions.lock().unwrap().mark_stopped(&key);
+    }
+    res?;
 
-    tracing::debug!(?key, "playing source");
     ctx.say("playing..").await?;
     Ok(())
 }
 
+// joins the voice channel, starts the player and registers the guild's session
+async fn start_session(
+    ctx: Context<'_>,
+    // for the guild to play in, held until the session is registered
+    reservation: Reservation,
+    location: VoiceLocation,
+    key: String,
+    started_by: UserId,
+    player_opts: PlayerOptions,
+    creds: Credentials,
+) -> Result<()> {
+    let guild_id = reservation.guild_id();
+    let voice_manager = songbird::get(ctx.serenity_context()).await.unwrap().clone();
+    let (call_handler_lock, res) = voice_manager
+        .join(guild_id, songbird::id::ChannelId(location.channel_id))
+        .await;
+    res?;
+
+    tracing::debug!(?key, "starting player");
+    let (spotify, pcm) = SpotifySession::connect(player_opts, creds)
+        .await
+        .inspect_err(|_| metrics::PLAYER_START_FAILURES.inc())?;
+    tokio::spawn(now_playing::announce(
+        Arc::clone(&ctx.serenity_context().http),
+        ctx.channel_id(),
+        key.clone(),
+        spotify.subscribe_events(),
+    ));
+    let events = spotify.subscribe_events();
+
+    // spotify streams at 44.1khz, we want 48khz, so resample it on the way into songbird
+    let resampler = Resampler::new(CountingReader::new(pcm))?;
+    let input = Input::new(
+        true,
+        Reader::Extension(Box::new(resampler)),
+        Codec::FloatPcm,
+        Container::Raw,
+        None,
+    );
+
+    let track = {
+        let mut call_handler = call_handler_lock.lock().await;
+        call_handler.play_source(input)
+    };
+    let session = PlaybackSession::new(
+        key.clone(),
+        started_by,
+        location,
+        spotify,
+        track,
+        call_handler_lock,
+    );
+    let previous = ctx
+        .data()
+        .sessions
+        .lock()
+        .unwrap()
+        .insert(guild_id, session);
+    if let Some(previous) = previous {
+        tracing::debug!(key = ?previous.key, "replacing previous playback");
+        let _ = previous.stop().await;
+    }
+
+    tracing::debug!(?key, "playing source");
+
+    tokio::spawn(panel::run(
+        Arc::clone(&ctx.serenity_context().http),
+        ctx.channel_id(),
+        guild_id,
+        key.clone(),
+        Arc::clone(&ctx.data().sessions),
+        events,
+    ));
+
+    // stop when the forwarder goes away, like a real device would
+    let forwarder_sessions = Arc::clone(&ctx.data().forwarder_sessions);
+    let sessions = Arc::clone(&ctx.data().sessions);
+    let http = Arc::clone(&ctx.serenity_context().http);
+    let channel_id = ctx.channel_id();
+    tokio::spawn(async move {
+        forwarder_sessions::gone(&forwarder_sessions, &key).await;
+        // someone may have stopped it and started something else in the meantime
+        let session = sessions.lock().unwrap().remove_if_key(guild_id, &key);
+        let Some(session) = session else {
+            return;
+        };
+        tracing::info!(?key, "forwarder went away, stopping playback");
+        let res = session.leave().await;
+        if let Err(err) = channel_id
+            .say(
+                &http,
+                format!(
+                    "The device for {key} went away, stopped playback ({})",
+                    describe_shutdown(&res)
+                ),
+            )
+            .await
+        {
+            tracing::warn!(?key, ?err, "failed to send message");
+        }
+    });
+
+    Ok(())
+}
+
 #[poise::command(slash_command)]
 async fn leave(ctx: Context<'_>) -> Result<()> {
     let guild = match ctx.guild() {
@@ -192,6 +501,20 @@ async fn leave(ctx: Context<'_>) -> Result<()> {
         }
         Some(g) => g,
     };
+    // ending someone else's stream is as good as controlling it
+    if let Some(owner) = session_owner(ctx.data(), guild.id) {
+        if !check_control(ctx, owner).await? {
+            return Ok(());
+        }
+    }
+
+    let session = ctx.data().sessions.lock().unwrap().remove(guild.id);
+    if let Some(session) = session {
+        let res = session.leave().await;
+        ctx.say(format!("left, {}", describe_shutdown(&res)))
+            .await?;
+        return Ok(());
+    }
 
     let voice_manager = songbird::get(ctx.serenity_context()).await.unwrap().clone();
     let call_handler_lock = voice_manager.get(guild.id);
@@ -204,8 +527,6 @@ async fn leave(ctx: Context<'_>) -> Result<()> {
     Ok(())
 }
 
-// TODO: it looks like /stop sometimes isnt making the player stop. i see gstreamer and player still alive in htop. why?
-// TODO: although, if you then actually play something on spotify, it hits the broken pipe -> exit(1)
 #[poise::command(slash_command)]
 async fn stop(ctx: Context<'_>) -> Result<()> {
     let guild = match ctx.guild() {
@@ -215,19 +536,584 @@ async fn stop(ctx: Context<'_>) -> Result<()> {
         }
         Some(g) => g,
     };
+    // ending someone else's stream is as good as controlling it
+    if let Some(owner) = session_owner(ctx.data(), guild.id) {
+        if !check_control(ctx, owner).await? {
+            return Ok(());
+        }
+    }
+
+    let session = ctx.data().sessions.lock().unwrap().remove(guild.id);
+    match session {
+        Some(session) => {
+            let res = session.stop().await;
+            ctx.say(format!("stopped playback, {}", describe_shutdown(&res)))
+                .await?;
+        }
+        None => {
+            ctx.say("Nothing is playing").await?;
+        }
+    }
+
+    Ok(())
+}
+
+// goes through spotify connect, so the slider in the owner's spotify client moves too (and moving it changes what
+// we report here)
+#[poise::command(slash_command)]
+async fn volume(
+    ctx: Context<'_>,
+    #[description = "Volume to set, 0-100. Leave empty to see the current volume"]
+    #[min = 0]
+    #[max = 100]
+    level: Option<u8>,
+) -> Result<()> {
+    let guild_id = match ctx.guild_id() {
+        None => {
+            ctx.say("This command can only be used in a guild").await?;
+            return Ok(());
+        }
+        Some(g) => g,
+    };
+    // same rule as the panel's volume buttons
+    if level.is_some() {
+        if let Some(owner) = session_owner(ctx.data(), guild_id) {
+            if !check_control(ctx, owner).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    let reply = {
+        let sessions = ctx.data().sessions.lock().unwrap();
+        match (sessions.get(guild_id), level) {
+            (None, _) => "Nothing is playing".to_string(),
+            (Some(session), None) => {
+                format!("volume is {}%", session.spotify().volume_percent())
+            }
+            (Some(session), Some(level)) => {
+                let volume = session.spotify().set_volume_percent(level);
+                format!("volume set to {}%", volume)
+            }
+        }
+    };
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+#[poise::command(slash_command)]
+async fn nowplaying(ctx: Context<'_>) -> Result<()> {
+    let guild_id = match ctx.guild_id() {
+        None => {
+            ctx.say("This command can only be used in a guild").await?;
+            return Ok(());
+        }
+        Some(g) => g,
+    };
+
+    let state = {
+        let sessions = ctx.data().sessions.lock().unwrap();
+        sessions.get(guild_id).map(|session| {
+            (
+                session.key.clone(),
+                session.player_options().device_name.clone(),
+                session.started_by,
+                session.spotify().now_playing(),
+            )
+        })
+    };
+    match state {
+        None => {
+            ctx.say("Nothing is playing").await?;
+        }
+        Some((key, device_name, _, None)) => {
+            ctx.say(format!(
+                "Connected as {device_name} with {key}, but spotify isn't playing anything on it"
+            ))
+            .await?;
+        }
+        Some((key, device_name, started_by, Some(now_playing))) => {
+            ctx.send(|r| {
+                r.embed(|e| {
+                    now_playing::now_playing_embed(e, &key, &device_name, started_by, &now_playing)
+                })
+            })
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+#[poise::command(slash_command)]
+async fn pause(ctx: Context<'_>) -> Result<()> {
+    control_playback(ctx, Control::Pause, "paused".to_string()).await
+}
+
+#[poise::command(slash_command)]
+async fn resume(ctx: Context<'_>) -> Result<()> {
+    control_playback(ctx, Control::Resume, "resumed".to_string()).await
+}
+
+#[poise::command(slash_command)]
+async fn skip(ctx: Context<'_>) -> Result<()> {
+    control_playback(ctx, Control::Next, "skipped".to_string()).await
+}
+
+#[poise::command(slash_command)]
+async fn previous(ctx: Context<'_>) -> Result<()> {
+    control_playback(
+        ctx,
+        Control::Previous,
+        "back to the previous track".to_string(),
+    )
+    .await
+}
+
+#[poise::command(slash_command)]
+async fn seek(
+    ctx: Context<'_>,
+    #[description = "Where to seek to, as mm:ss"] position: String,
+) -> Result<()> {
+    let Some(position) = now_playing::parse_duration(&position) else {
+        ctx.say(format!(
+            "Can't make sense of {position}, try something like 1:23"
+        ))
+        .await?;
+        return Ok(());
+    };
+    control_playback(
+        ctx,
+        Control::Seek(position),
+        format!("seeking to {}", now_playing::format_duration(position)),
+    )
+    .await
+}
+
+// sends a transport control to the guild's player, if the invoker is allowed to
+async fn control_playback(ctx: Context<'_>, control: Control, done: String) -> Result<()> {
+    let guild_id = match ctx.guild_id() {
+        None => {
+            ctx.say("This command can only be used in a guild").await?;
+            return Ok(());
+        }
+        Some(g) => g,
+    };
+
+    let Some(owner) = session_owner(ctx.data(), guild_id) else {
+        ctx.say("Nothing is playing").await?;
+        return Ok(());
+    };
+    if !check_control(ctx, owner).await? {
+        return Ok(());
+    }
+
+    let reply = {
+        let sessions = ctx.data().sessions.lock().unwrap();
+        match sessions.get(guild_id) {
+            None => "Nothing is playing".to_string(),
+            Some(session) => match (control, session.spotify().now_playing()) {
+                (_, None) => "Spotify isn't playing anything here".to_string(),
+                (Control::Seek(position), Some(now_playing))
+                    if position > now_playing.track.duration =>
+                {
+                    format!(
+                        "The track is only {} long",
+                        now_playing::format_duration(now_playing.track.duration)
+                    )
+                }
+                _ => {
+                    tracing::debug!(key = ?session.key, ?control, "controlling playback");
+                    session.spotify().control(control);
+                    done
+                }
+            },
+        }
+    };
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+// who started the guild's stream, if anything is playing
+fn session_owner(data: &Data, guild_id: GuildId) -> Option<UserId> {
+    data.sessions
+        .lock()
+        .unwrap()
+        .get(guild_id)
+        .map(|session| session.started_by)
+}
+
+// whether the invoker may control the stream `owner` started, telling them if not
+async fn check_control(ctx: Context<'_>, owner: UserId) -> Result<bool> {
+    let roles = ctx
+        .author_member()
+        .await
+        .map(|member| member.roles.clone())
+        .unwrap_or_default();
+    if may_control(ctx.data(), ctx.author().id, &roles, owner) {
+        return Ok(true);
+    }
+    ctx.say(format!(
+        "Only <@{owner}> or members with the control role can do that"
+    ))
+    .await?;
+    Ok(false)
+}
+
+// transport controls are for whoever started the stream, and the control role if there is one
+fn may_control(data: &Data, user: UserId, roles: &[RoleId], owner: UserId) -> bool {
+    user == owner
+        || data
+            .control_role
+            .is_some_and(|control_role| roles.contains(&control_role))
+}
+
+async fn event_handler(
+    ctx: &serenity::Context,
+    event: &poise::Event<'_>,
+    data: &Data,
+) -> Result<()> {
+    match event {
+        poise::Event::InteractionCreate {
+            interaction: Interaction::MessageComponent(interaction),
+        } => {
+            if let Some((action, key)) = panel::parse_custom_id(&interaction.data.custom_id) {
+                let res = data
+                    .command_limiter
+                    .lock()
+                    .unwrap()
+                    .check(interaction.user.id, Instant::now());
+                if let Err(retry_after) = res {
+                    metrics::RATE_LIMITED.with_label_values(&["command"]).inc();
+                    return respond_ephemeral(ctx, interaction, &slow_down_message(retry_after))
+                        .await;
+                }
+                metrics::COMMANDS
+                    .with_label_values(&[&format!("panel_{}", action.id())])
+                    .inc();
+                handle_panel(ctx, interaction, data, action, key).await?;
+            }
+        }
+        poise::Event::Ready { .. } | poise::Event::Resume { .. } => {
+            metrics::DISCORD_CONNECTED.set(1);
+        }
+        poise::Event::ShardStageUpdate { update } => {
+            tracing::debug!(?update, "shard stage changed");
+            let connected = matches!(update.new, ::serenity::gateway::ConnectionStage::Connected);
+            metrics::DISCORD_CONNECTED.set(connected as i64);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// a press on one of the control panel's buttons. the panel updates itself from the player's events, except for
+// volume which spotify doesn't tell us about
+async fn handle_panel(
+    ctx: &serenity::Context,
+    interaction: &MessageComponentInteraction,
+    data: &Data,
+    action: Action,
+    key: &str,
+) -> Result<()> {
+    let Some(guild_id) = interaction.guild_id else {
+        return Ok(());
+    };
+    let owner = data
+        .sessions
+        .lock()
+        .unwrap()
+        .get(guild_id)
+        .filter(|session| session.key == key)
+        .map(|session| session.started_by);
+    let Some(owner) = owner else {
+        return respond_ephemeral(ctx, interaction, "That stream isn't playing anymore").await;
+    };
+    let roles = interaction
+        .member
+        .as_ref()
+        .map(|member| member.roles.as_slice())
+        .unwrap_or_default();
+    if !may_control(data, interaction.user.id, roles, owner) {
+        return respond_ephemeral(
+            ctx,
+            interaction,
+            &format!("Only <@{owner}> or members with the control role can do that"),
+        )
+        .await;
+    }
+    tracing::debug!(?key, ?action, user = ?interaction.user.id, "control panel pressed");
+
+    if action == Action::Stop {
+        interaction
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(InteractionResponseType::DeferredUpdateMessage)
+            })
+            .await?;
+        let session = data.sessions.lock().unwrap().remove_if_key(guild_id, key);
+        if let Some(session) = session {
+            let _ = session.stop().await;
+        }
+        return Ok(());
+    }
+
+    // None if the session went away since we checked
+    let volume = {
+        let sessions = data.sessions.lock().unwrap();
+        sessions
+            .get(guild_id)
+            .filter(|session| session.key == key)
+            .map(|session| {
+                let spotify = session.spotify();
+                let volume = spotify.volume_percent();
+                match action {
+                    Action::Previous => spotify.control(Control::Previous),
+                    Action::Next => spotify.control(Control::Next),
+                    Action::PlayPause => match spotify.now_playing() {
+                        Some(now_playing) if !now_playing.paused => spotify.control(Control::Pause),
+                        _ => spotify.control(Control::Resume),
+                    },
+                    Action::VolumeDown => {
+                        return spotify
+                            .set_volume_percent(volume.saturating_sub(panel::VOLUME_STEP))
+                    }
+                    Action::VolumeUp => {
+                        return spotify
+                            .set_volume_percent(volume.saturating_add(panel::VOLUME_STEP))
+                    }
+                    Action::Stop => unreachable!(),
+                }
+                volume
+            })
+    };
+
+    match (action, volume) {
+        (Action::VolumeDown | Action::VolumeUp, Some(volume)) => {
+            let now_playing = panel::snapshot(&data.sessions, guild_id, key)
+                .and_then(|(now_playing, _)| now_playing);
+            let text = panel::content(key, Some((now_playing.as_ref(), volume)));
+            interaction
+                .create_interaction_response(&ctx.http, |r| {
+                    r.kind(InteractionResponseType::UpdateMessage)
+                        .interaction_response_data(|d| d.content(text))
+                })
+                .await?;
+        }
+        _ => {
+            interaction
+                .create_interaction_response(&ctx.http, |r| {
+                    r.kind(InteractionResponseType::DeferredUpdateMessage)
+                })
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn respond_ephemeral(
+    ctx: &serenity::Context,
+    interaction: &MessageComponentInteraction,
+    text: &str,
+) -> Result<()> {
+    interaction
+        .create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::ChannelMessageWithSource)
+                .interaction_response_data(|d| d.content(text).ephemeral(true))
+        })
+        .await?;
+    Ok(())
+}
+
+// for when the bot gets into a state where it can't play anymore in this guild: throws away the voice connection
+// and the player, and starts them again with the same stream
+#[poise::command(slash_command)]
+async fn reset(ctx: Context<'_>) -> Result<()> {
+    let guild_id = match ctx.guild_id() {
+        None => {
+            ctx.say("This command can only be used in a guild").await?;
+            return Ok(());
+        }
+        Some(g) => g,
+    };
+    // restarting someone else's stream is as good as controlling it
+    if let Some(owner) = session_owner(ctx.data(), guild_id) {
+        if !check_control(ctx, owner).await? {
+            return Ok(());
+        }
+    }
+    ctx.defer().await?;
+
+    // keep the guild's slot while it's torn down, so it can't be taken in the meantime
+    let reservation = SessionManager::reserve(&ctx.data().sessions, guild_id);
+    let session = ctx
+        .data()
+        .sessions
+        .lock()
+        .unwrap()
+        .take_for_restart(guild_id);
+    let restart = match session {
+        Some(session) => {
+            let channel = session.call().lock().await.current_channel();
+            // the bot may have been moved since
+            let location = channel.map(|channel| match ctx.guild() {
+                Some(guild) => voice_location(&guild, ChannelId(channel.0)),
+                None => VoiceLocation {
+                    channel_id: channel.0,
+                    ..session.location.clone()
+                },
+            });
+            let key = session.key.clone();
+            let started_by = session.started_by;
+            let player_opts = session.player_options().clone();
+            let creds = session.reusable_creds().clone();
+            let res = session.leave().await;
+            tracing::debug!(?key, ?res, "tore down session for reset");
+            if location.is_none() {
+                ctx.data().sessions.lock().unwrap().mark_stopped(&key);
+            }
+            location.map(|location| (location, key, started_by, player_opts, creds))
+        }
+        None => None,
+    };
+
     let voice_manager = songbird::get(ctx.serenity_context()).await.unwrap().clone();
-    let call_handler_lock = voice_manager.get(guild.id);
-    if let Some(call_handler_lock) = call_handler_lock {
-        let mut call_handler = call_handler_lock.lock().await;
-        call_handler.stop();
-        ctx.say("stopped playback").await?;
+    if let Err(err) = voice_manager.remove(guild_id).await {
+        tracing::debug!(?err, "no voice connection to remove");
     }
 
+    match (restart, reservation) {
+        (Some((location, key, started_by, player_opts, creds)), Some(reservation)) => {
+            let res = start_session(
+                ctx,
+                reservation,
+                location,
+                key.clone(),
+                started_by,
+                player_opts,
+                creds,
+            )
+            .await;
+            if res.is_err() {
+                ctx.data().sessions.lock().unwrap().mark_stopped(&key);
+            }
+            res?;
+            ctx.say("reset, playing again").await?;
+        }
+        (restart, _) => {
+            if let Some((_, key, ..)) = restart {
+                ctx.data().sessions.lock().unwrap().mark_stopped(&key);
+            }
+            ctx.say("reset, nothing was playing").await?;
+        }
+    }
+    Ok(())
+}
+
+fn voice_location(guild: &Guild, channel_id: ChannelId) -> VoiceLocation {
+    VoiceLocation {
+        guild_id: guild.id.0,
+        guild_name: guild.name.clone(),
+        channel_id: channel_id.0,
+        channel_name: guild
+            .channels
+            .get(&channel_id)
+            .and_then(|channel| channel.clone().guild())
+            .map(|channel| channel.name)
+            .unwrap_or_else(|| channel_id.to_string()),
+    }
+}
+
+fn full_message(sessions: &Mutex<SessionManager>) -> String {
+    format!(
+        "All {} playback slots are in use, try again once someone stops playing",
+        sessions.lock().unwrap().max_sessions()
+    )
+}
+
+// pairs a forwarder run with --link with the user, so the streams it sends are theirs and /play_spotify finds
+// them without a key
+#[poise::command(slash_command)]
+async fn link(
+    ctx: Context<'_>,
+    #[description = "Code the forwarder printed"] code: String,
+) -> Result<()> {
+    if locked_out(ctx).await? {
+        return Ok(());
+    }
+    let linked = ctx
+        .data()
+        .links
+        .lock()
+        .unwrap()
+        .confirm(&code, ctx.author().id.0);
+    let reply = match linked {
+        Ok(Some(link)) => {
+            tracing::info!(user = ?ctx.author().id, device = ?link.device_name, "linked forwarder");
+            format!(
+                "Linked {} to you. Once you connect to it from spotify, /play_spotify will play it",
+                link.device_name
+            )
+        }
+        Ok(None) => format!(
+            "No forwarder is waiting on the code {code}{}",
+            record_failure(ctx)
+        ),
+        Err(err) => {
+            tracing::warn!(?err, "failed to save link");
+            "Linked, but the link couldn't be saved and will be forgotten when the bot restarts"
+                .to_string()
+        }
+    };
+    ctx.send(|m| m.content(reply).ephemeral(true)).await?;
     Ok(())
 }
 
-// HACK: there's a bug that makes the bot get into a state where it cant play anymore. so let users unblock themselves
+// forgets every forwarder linked to the user. they'll need to be run with --link again
 #[poise::command(slash_command)]
-async fn restart(_ctx: Context<'_>) -> Result<()> {
+async fn unlink(ctx: Context<'_>) -> Result<()> {
+    let removed = ctx.data().links.lock().unwrap().unlink(ctx.author().id.0);
+    let reply = match removed {
+        Ok(devices) if devices.is_empty() => "You have no linked forwarders".to_string(),
+        Ok(devices) => format!("Unlinked {}", devices.join(", ")),
+        Err(err) => {
+            tracing::warn!(?err, "failed to save links");
+            "Unlinked, but that couldn't be saved and will be undone when the bot restarts"
+                .to_string()
+        }
+    };
+    ctx.send(|m| m.content(reply).ephemeral(true)).await?;
+    Ok(())
+}
+
+// lists every guild's playback, for whoever runs the bot
+#[poise::command(slash_command, rename = "sessions", owners_only, hide_in_help)]
+async fn list_sessions(ctx: Context<'_>) -> Result<()> {
+    let (sessions, max_sessions) = {
+        let manager = ctx.data().sessions.lock().unwrap();
+        (manager.list(), manager.max_sessions())
+    };
+    let mut reply = format!("{}/{} sessions", sessions.len(), max_sessions);
+    for (key, info) in sessions {
+        let guild = GuildId(info.guild_id)
+            .name(ctx.serenity_context())
+            .unwrap_or_else(|| info.guild_id.to_string());
+        let track = match &info.track {
+            Some(track) if track.paused => format!("paused on {}", track.title),
+            Some(track) => format!("playing {}", track.title),
+            None => "idle".to_string(),
+        };
+        reply.push_str(&format!(
+            "\n- {guild}: `{}` from {}, started by <@{}> <t:{}:R>, {track}",
+            key, info.device_name, info.started_by, info.started_at
+        ));
+    }
+    ctx.send(|r| r.content(reply).ephemeral(true)).await?;
+    Ok(())
+}
+
+// restarts the whole receiver, dropping every guild's playback and every pending stream key
+#[poise::command(slash_command, owners_only, hide_in_help)]
+async fn restart(ctx: Context<'_>) -> Result<()> {
+    tracing::warn!(user = ?ctx.author().id, "restart requested");
+    ctx.say("restarting").await?;
     std::process::exit(0);
 }
@@ -1,22 +1,207 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Default)]
+use protocol::VoiceLocation;
+
+// how long we remember what happened to a key once it's no longer pending, for status queries
+const HISTORY_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+struct PendingCreds {
+    req: protocol::ForwardCreds,
+    inserted_at: Instant,
+}
+
+// why /play_spotify couldn't have a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TakeError {
+    // never sent, or expired
+    NotFound,
+    // the forwarder bound it to someone else, who can still claim it
+    WrongUser { owner: u64 },
+}
+
+// why forwarded creds couldn't be stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertError {
+    // someone else's creds are waiting on the key
+    InUse,
+    // too many keys are waiting already
+    Full,
+}
+
+#[derive(Debug)]
 pub struct CredsRegistry {
-    creds: HashMap<String, protocol::ForwardCreds>,
+    creds: HashMap<String, PendingCreds>,
+    ttl: Duration,
+    max_pending: usize,
+    // keys that were taken by /play_spotify, and where
+    claimed: HashMap<String, (VoiceLocation, Instant)>,
+    // keys nobody took in time
+    expired: HashMap<String, Instant>,
 }
 
 impl CredsRegistry {
-    // will NOT overwrite a key. false if key already exists and the insert failed
-    pub fn insert(&mut self, req: protocol::ForwardCreds) -> bool {
+    pub fn new(ttl: Duration, max_pending: usize) -> Self {
+        Self {
+            creds: HashMap::new(),
+            ttl,
+            max_pending,
+            claimed: HashMap::new(),
+            expired: HashMap::new(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    // will NOT overwrite a key
+    pub fn insert(&mut self, req: protocol::ForwardCreds) -> Result<(), InsertError> {
         let key = req.key.clone();
-        if self.creds.contains_key(&key) {
-            return false;
+        if self.creds.get(&key).is_some_and(|p| !self.is_expired(p)) {
+            return Err(InsertError::InUse);
+        }
+        if self.pending() >= self.max_pending {
+            return Err(InsertError::Full);
+        }
+        self.claimed.remove(&key);
+        self.expired.remove(&key);
+        self.creds.insert(
+            key,
+            PendingCreds {
+                req,
+                inserted_at: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    // hands the creds to the discord user claiming them, if the key is theirs to claim
+    pub fn take(&mut self, key: &str, user: u64) -> Result<protocol::ForwardCreds, TakeError> {
+        let pending = self.creds.get(key).ok_or(TakeError::NotFound)?;
+        if !self.is_expired(pending) {
+            if let Some(owner) = pending.req.discord_user.filter(|&owner| owner != user) {
+                return Err(TakeError::WrongUser { owner });
+            }
+        }
+        self.discard(key).ok_or(TakeError::NotFound)
+    }
+
+    // removes the key whoever it belongs to. None if it wasn't pending
+    pub fn discard(&mut self, key: &str) -> Option<protocol::ForwardCreds> {
+        let pending = self.creds.remove(key)?;
+        // the sweeper may not have gotten to it yet
+        if self.is_expired(&pending) {
+            self.expired.insert(key.to_string(), Instant::now());
+            return None;
+        }
+        Some(pending.req)
+    }
+
+    // remember where a key we handed out went
+    pub fn mark_claimed(&mut self, key: &str, location: VoiceLocation) {
+        self.claimed
+            .insert(key.to_string(), (location, Instant::now()));
+    }
+
+    // how long until the key expires, if it's pending
+    pub fn expires_in(&self, key: &str) -> Option<Duration> {
+        let pending = self.creds.get(key)?;
+        self.ttl.checked_sub(pending.inserted_at.elapsed())
+    }
+
+    pub fn claimed_at(&self, key: &str) -> Option<&VoiceLocation> {
+        self.claimed.get(key).map(|(location, _)| location)
+    }
+
+    pub fn is_expired_key(&self, key: &str) -> bool {
+        self.expired.contains_key(key) || self.creds.get(key).is_some_and(|p| self.is_expired(p))
+    }
+
+    // the most recently forwarded key bound to the user, for /play_spotify without a key
+    pub fn latest_for(&self, user: u64) -> Option<&str> {
+        self.creds
+            .iter()
+            .filter(|(_, p)| p.req.discord_user == Some(user) && !self.is_expired(p))
+            .max_by_key(|(_, p)| p.inserted_at)
+            .map(|(key, _)| key.as_str())
+    }
+
+    // keys waiting to be claimed
+    pub fn pending(&self) -> usize {
+        self.creds.values().filter(|p| !self.is_expired(p)).count()
+    }
+
+    // drop all entries older than the ttl. returns the number of entries evicted
+    pub fn evict_expired(&mut self) -> usize {
+        let before = self.creds.len();
+        let ttl = self.ttl;
+        let now = Instant::now();
+        let expired = &mut self.expired;
+        self.creds.retain(|key, p| {
+            let keep = p.inserted_at.elapsed() < ttl;
+            if !keep {
+                expired.insert(key.clone(), now);
+            }
+            keep
+        });
+        self.expired.retain(|_, at| at.elapsed() < HISTORY_TTL);
+        self.claimed.retain(|_, (_, at)| at.elapsed() < HISTORY_TTL);
+        before - self.creds.len()
+    }
+
+    fn is_expired(&self, pending: &PendingCreds) -> bool {
+        pending.inserted_at.elapsed() >= self.ttl
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward_creds(key: &str, discord_user: Option<u64>) -> protocol::ForwardCreds {
+        protocol::ForwardCreds {
+            device_name: "danube".to_string(),
+            key: key.to_string(),
+            creds: protocol::ForwardedCreds::Plain(player::Credentials::with_password(
+                "user", "pass",
+            )),
+            discord_user,
+            link_token: None,
         }
-        self.creds.insert(key, req);
-        true
     }
 
-    pub fn take(&mut self, key: &str) -> Option<protocol::ForwardCreds> {
-        self.creds.remove(key)
+    #[test]
+    fn test_take() {
+        let mut registry = CredsRegistry::new(Duration::from_secs(60), 2);
+        assert!(registry.insert(forward_creds("anyone", None)).is_ok());
+        assert!(registry.insert(forward_creds("owned", Some(1))).is_ok());
+        assert_eq!(
+            registry.insert(forward_creds("owned", None)),
+            Err(InsertError::InUse)
+        );
+
+        assert!(registry.take("anyone", 2).is_ok());
+        assert_eq!(registry.take("anyone", 2).unwrap_err(), TakeError::NotFound);
+        // the wrong user doesn't use the key up
+        assert_eq!(
+            registry.take("owned", 2).unwrap_err(),
+            TakeError::WrongUser { owner: 1 }
+        );
+        assert!(registry
+            .insert(forward_creds("also-owned", Some(1)))
+            .is_ok());
+        assert_eq!(
+            registry.insert(forward_creds("one-too-many", None)),
+            Err(InsertError::Full)
+        );
+        assert_eq!(registry.latest_for(1), Some("also-owned"));
+        assert_eq!(registry.latest_for(2), None);
+        assert_eq!(registry.take("owned", 1).unwrap().key, "owned");
+        assert_eq!(
+            registry.take("missing", 1).unwrap_err(),
+            TakeError::NotFound
+        );
     }
 }
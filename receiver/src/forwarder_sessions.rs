@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use ring::constant_time;
+use tokio::sync::watch;
+
+use crate::links::hex;
+
+// how long a forwarder has to open its socket, or to reconnect after losing it, before its device counts as gone
+pub const CONNECT_DEADLINE: Duration = protocol::HEARTBEAT_TIMEOUT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwarderState {
+    // forwarded the key but hasn't got a socket open, yet or since losing it
+    Waiting,
+    Connected,
+    Gone,
+}
+
+#[derive(Debug)]
+struct Entry {
+    // handed to the forwarder that posted the key, so only it can hold the key's socket
+    secret: String,
+    state: watch::Sender<ForwarderState>,
+    // when it last started waiting
+    since: Instant,
+}
+
+impl Entry {
+    fn is_stale(&self, now: Instant) -> bool {
+        *self.state.borrow() == ForwarderState::Waiting
+            && now.duration_since(self.since) >= CONNECT_DEADLINE
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectError {
+    // no forwarder posted the key, or the secret is someone else's
+    Unknown,
+    // the key's forwarder already has a socket open
+    AlreadyConnected,
+}
+
+// tracks whether the forwarder that sent a key is still around. the forwarder holds a websocket open
+// while its device is alive; when that goes away, whoever is playing the key gets told to stop.
+#[derive(Debug, Default)]
+pub struct ForwarderSessions {
+    sessions: HashMap<String, Entry>,
+}
+
+impl ForwarderSessions {
+    // whether a forwarder could post this key now, ie no other forwarder is still around for it
+    pub fn is_free(&self, key: &str) -> bool {
+        self.sessions
+            .get(key)
+            .is_none_or(|entry| entry.is_stale(Instant::now()))
+    }
+
+    // for a forwarder that posted a key. returns the secret it needs to open the key's socket, or None if another
+    // forwarder is still around for the key
+    pub fn register(&mut self, key: &str) -> Option<String> {
+        let now = Instant::now();
+        self.prune(now);
+        if self.sessions.contains_key(key) {
+            return None;
+        }
+        let secret = hex(&rand::thread_rng().gen::<[u8; 32]>());
+        self.sessions.insert(
+            key.to_string(),
+            Entry {
+                secret: secret.clone(),
+                state: watch::channel(ForwarderState::Waiting).0,
+                since: now,
+            },
+        );
+        Some(secret)
+    }
+
+    // keys nobody connected to in time, which are gone as far as anyone's concerned
+    fn prune(&mut self, now: Instant) {
+        self.sessions.retain(|_, entry| {
+            let stale = entry.is_stale(now);
+            if stale {
+                entry.state.send_replace(ForwarderState::Gone);
+            }
+            !stale
+        });
+    }
+
+    // a key that nobody registered is already gone
+    pub fn subscribe(&self, key: &str) -> watch::Receiver<ForwarderState> {
+        match self.sessions.get(key) {
+            Some(entry) => entry.state.subscribe(),
+            None => watch::channel(ForwarderState::Gone).1,
+        }
+    }
+
+    pub fn connect(&mut self, key: &str, secret: &str) -> Result<(), ConnectError> {
+        let entry = self.sessions.get(key).ok_or(ConnectError::Unknown)?;
+        if constant_time::verify_slices_are_equal(entry.secret.as_bytes(), secret.as_bytes())
+            .is_err()
+        {
+            return Err(ConnectError::Unknown);
+        }
+        if *entry.state.borrow() != ForwarderState::Waiting {
+            return Err(ConnectError::AlreadyConnected);
+        }
+        entry.state.send_replace(ForwarderState::Connected);
+        Ok(())
+    }
+
+    // the socket dropped, but the forwarder may yet reconnect
+    pub fn lost(&mut self, key: &str) {
+        if let Some(entry) = self.sessions.get_mut(key) {
+            entry.since = Instant::now();
+            entry.state.send_replace(ForwarderState::Waiting);
+        }
+    }
+
+    // the forwarder said goodbye, or took too long to come back
+    pub fn disconnected(&mut self, key: &str) {
+        if let Some(entry) = self.sessions.remove(key) {
+            // no receivers just means nobody is playing it
+            entry.state.send_replace(ForwarderState::Gone);
+        }
+    }
+
+    // gives up on a forwarder that didn't connect in time
+    fn expire(&mut self, key: &str) {
+        if self
+            .sessions
+            .get(key)
+            .is_some_and(|entry| entry.is_stale(Instant::now()))
+        {
+            self.disconnected(key);
+        }
+    }
+}
+
+// resolves once the forwarder for the key has gone away, or hasn't had its socket open for the connect deadline
+pub async fn gone(sessions: &Mutex<ForwarderSessions>, key: &str) {
+    let mut state = sessions.lock().unwrap().subscribe(key);
+    loop {
+        let connected = tokio::time::timeout(
+            CONNECT_DEADLINE,
+            state.wait_for(|s| *s != ForwarderState::Waiting),
+        )
+        .await
+        .map(|res| res.map(|s| *s));
+        match connected {
+            Ok(Ok(ForwarderState::Connected)) => {}
+            Ok(_) => return,
+            Err(_) => {
+                sessions.lock().unwrap().expire(key);
+                // it may have connected right at the deadline
+                if *state.borrow() == ForwarderState::Gone {
+                    return;
+                }
+                continue;
+            }
+        }
+        if state
+            .wait_for(|s| *s != ForwarderState::Connected)
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect() {
+        let mut sessions = ForwarderSessions::default();
+        let secret = sessions.register("key").unwrap();
+        assert!(!sessions.is_free("key"));
+        assert_eq!(sessions.register("key"), None);
+        let state = sessions.subscribe("key");
+
+        assert_eq!(sessions.connect("key", "guess"), Err(ConnectError::Unknown));
+        assert_eq!(
+            sessions.connect("other", &secret),
+            Err(ConnectError::Unknown)
+        );
+        assert_eq!(sessions.connect("key", &secret), Ok(()));
+        assert_eq!(*state.borrow(), ForwarderState::Connected);
+        assert_eq!(
+            sessions.connect("key", &secret),
+            Err(ConnectError::AlreadyConnected)
+        );
+
+        // it can come back after a blip
+        sessions.lost("key");
+        assert_eq!(*state.borrow(), ForwarderState::Waiting);
+        assert_eq!(sessions.connect("key", &secret), Ok(()));
+
+        sessions.disconnected("key");
+        assert_eq!(*state.borrow(), ForwarderState::Gone);
+        assert!(sessions.is_free("key"));
+        assert_eq!(sessions.connect("key", &secret), Err(ConnectError::Unknown));
+        assert_eq!(*sessions.subscribe("key").borrow(), ForwarderState::Gone);
+    }
+}
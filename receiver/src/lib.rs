@@ -1,3 +1,13 @@
+pub mod api_error;
+pub mod auth;
 pub mod bot;
 pub mod creds_registry;
+pub mod forwarder_sessions;
+pub mod links;
+pub mod metrics;
+pub mod now_playing;
+pub mod panel;
+pub mod rate_limit;
+pub mod resampler;
 pub mod server;
+pub mod sessions;
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use protocol::LinkStatus;
+use rand::Rng;
+
+// how long a pairing code has to be typed into /link
+pub const CODE_TTL: Duration = Duration::from_secs(5 * 60);
+// no 0/O or 1/I, the code gets read off a terminal. 32 of them, so 8 make 40 bits
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+const CODE_LEN: usize = 8;
+
+// a forwarder paired with a discord user through /link
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct Link {
+    pub discord_user: u64,
+    pub device_name: String,
+    // unix seconds
+    pub linked_at: u64,
+}
+
+#[derive(Debug)]
+struct PendingLink {
+    token: String,
+    device_name: String,
+    created_at: Instant,
+}
+
+// forwarders that are paired with discord users, by the token they present, plus the codes waiting to be
+// confirmed. links are saved to a file if there is one, so they survive restarts
+#[derive(Debug, Default)]
+pub struct LinkRegistry {
+    pending: HashMap<String, PendingLink>,
+    links: HashMap<String, Link>,
+    path: Option<PathBuf>,
+}
+
+impl LinkRegistry {
+    // without a path links are forgotten on restart
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let links = match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("failed to parse links file {}", path))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read links file {}", path))
+            }
+        };
+        Ok(Self {
+            pending: HashMap::new(),
+            links,
+            path: Some(path.into()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    // a new pairing code, and the token the forwarder will use once it's confirmed
+    pub fn start(&mut self, device_name: String) -> (String, String) {
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code: String = (0..CODE_LEN)
+                .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+                .collect();
+            if !self.pending.contains_key(&code) {
+                break code;
+            }
+        };
+        let token = hex(&rng.gen::<[u8; 32]>());
+        self.pending.insert(
+            code.clone(),
+            PendingLink {
+                token: token.clone(),
+                device_name,
+                created_at: Instant::now(),
+            },
+        );
+        (display_code(&code), token)
+    }
+
+    // pairs the forwarder that was given the code with the user. None if there's no such code
+    pub fn confirm(&mut self, code: &str, discord_user: u64) -> Result<Option<Link>> {
+        let code = normalize_code(code);
+        let Some(pending) = self.pending.remove(&code) else {
+            return Ok(None);
+        };
+        if pending.created_at.elapsed() >= CODE_TTL {
+            return Ok(None);
+        }
+        let link = Link {
+            discord_user,
+            device_name: pending.device_name,
+            linked_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        self.links.insert(pending.token, link.clone());
+        self.save()?;
+        Ok(Some(link))
+    }
+
+    pub fn status(&self, token: &str) -> LinkStatus {
+        if let Some(link) = self.links.get(token) {
+            return LinkStatus::Linked {
+                discord_user: link.discord_user,
+            };
+        }
+        let pending = self
+            .pending
+            .values()
+            .find(|pending| pending.token == token)
+            .and_then(|pending| CODE_TTL.checked_sub(pending.created_at.elapsed()));
+        match pending {
+            Some(expires_in) => LinkStatus::Pending {
+                expires_in_secs: expires_in.as_secs(),
+            },
+            None => LinkStatus::Unknown,
+        }
+    }
+
+    // who the forwarder presenting this token is linked to
+    pub fn user_for(&self, token: &str) -> Option<u64> {
+        self.links.get(token).map(|link| link.discord_user)
+    }
+
+    // forgets every forwarder linked to the user, returning their device names
+    pub fn unlink(&mut self, discord_user: u64) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        self.links.retain(|_, link| {
+            let keep = link.discord_user != discord_user;
+            if !keep {
+                removed.push(link.device_name.clone());
+            }
+            keep
+        });
+        if !removed.is_empty() {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    // drop codes nobody confirmed in time
+    pub fn evict_expired(&mut self) {
+        self.pending
+            .retain(|_, pending| pending.created_at.elapsed() < CODE_TTL);
+    }
+
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // write then rename, so a crash can't leave half a file
+        let tmp = path.with_extension("tmp");
+        write_private(&tmp, &serde_json::to_vec_pretty(&self.links)?)
+            .with_context(|| format!("failed to write links file {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("failed to write links file {}", path.display()))?;
+        Ok(())
+    }
+}
+
+// readable only by us, since the tokens are as good as the spotify accounts they forward
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    // the mode only applies to new files, so don't reuse one left behind by a crash
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+// codes are shown as ABCD-EFGH, but typing them any which way is fine
+fn display_code(code: &str) -> String {
+    let (a, b) = code.split_at(CODE_LEN / 2);
+    format!("{a}-{b}")
+}
+
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link() {
+        let path = std::env::temp_dir().join(format!("links-test-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut links = LinkRegistry::load(Some(path)).unwrap();
+
+        let (code, token) = links.start("danube".to_string());
+        assert!(matches!(links.status(&token), LinkStatus::Pending { .. }));
+        assert_eq!(
+            links.confirm("nope", 1).unwrap().map(|l| l.discord_user),
+            None
+        );
+        let typed = code.to_lowercase().replace('-', " ");
+        assert_eq!(
+            links.confirm(&typed, 1).unwrap().unwrap().device_name,
+            "danube"
+        );
+        // codes are single use
+        assert!(links.confirm(&code, 2).unwrap().is_none());
+        assert_eq!(links.status(&token), LinkStatus::Linked { discord_user: 1 });
+        assert_eq!(links.user_for(&token), Some(1));
+
+        // only we can read the tokens
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // survives a restart
+        let mut links = LinkRegistry::load(Some(path)).unwrap();
+        assert_eq!(links.user_for(&token), Some(1));
+        assert_eq!(links.unlink(1).unwrap(), vec!["danube".to_string()]);
+        assert_eq!(links.status(&token), LinkStatus::Unknown);
+        assert!(LinkRegistry::load(Some(path)).unwrap().is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
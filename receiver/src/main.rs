@@ -1,10 +1,18 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
 use anyhow::Result;
+use protocol::CredsKey;
 
 use clap::Parser;
 
-use receiver::{bot::BotOptions, creds_registry::CredsRegistry};
+use receiver::{
+    auth::ApiTokens, bot::BotOptions, creds_registry::CredsRegistry,
+    forwarder_sessions::ForwarderSessions, links::LinkRegistry, rate_limit::ApiRateLimit,
+    sessions::SessionManager,
+};
 
 #[derive(Debug, Parser)]
 struct Options {
@@ -15,6 +23,61 @@ struct Options {
         help = "port for the http server to listen on"
     )]
     port: u16,
+    #[clap(
+        long,
+        env,
+        default_value = "600",
+        help = "how long in seconds an unclaimed stream key stays valid"
+    )]
+    key_ttl: u64,
+    #[clap(
+        long,
+        env,
+        value_delimiter = ',',
+        help = "comma-separated api tokens forwarders must present as a bearer token"
+    )]
+    api_tokens: Vec<String>,
+    #[clap(long, env, help = "file containing api tokens, one per line")]
+    api_tokens_file: Option<String>,
+    #[clap(
+        long,
+        env,
+        default_value = "1000",
+        help = "how many stream keys can be waiting to be claimed at once"
+    )]
+    max_pending_keys: usize,
+    #[clap(
+        long,
+        env,
+        default_value = "10",
+        help = "how many guilds can be playing at once"
+    )]
+    max_sessions: usize,
+    #[clap(
+        long,
+        env,
+        default_value = "120",
+        help = "how many api requests a minute each ip address may make, 0 for no limit"
+    )]
+    api_rate: u32,
+    #[clap(
+        long,
+        env,
+        help = "rate limit by the X-Forwarded-For header rather than the peer address, for running behind a reverse proxy"
+    )]
+    trust_forwarded_for: bool,
+    #[clap(
+        long,
+        env,
+        help = "file to keep /link pairings in, so they survive restarts"
+    )]
+    links_file: Option<String>,
+    #[clap(
+        long,
+        env,
+        help = "reject forwarders that send spotify credentials unsealed, ie from before sealing was added"
+    )]
+    require_sealed_creds: bool,
     #[clap(flatten)]
     bot_opts: BotOptions,
 }
@@ -26,13 +89,72 @@ async fn main() -> Result<()> {
 
     let opts = Options::parse();
 
-    let stream_registry = Arc::new(RwLock::new(CredsRegistry::default()));
+    let api_tokens = ApiTokens::load(&opts.api_tokens, opts.api_tokens_file.as_deref())?;
+    if api_tokens.is_empty() {
+        tracing::warn!("no api tokens configured, anyone can forward creds to this receiver");
+    } else {
+        tracing::info!("loaded {} api tokens", api_tokens.len());
+    }
+
+    let api_rate_limit = ApiRateLimit::new(opts.api_rate, opts.trust_forwarded_for);
+
+    let links = LinkRegistry::load(opts.links_file.as_deref())?;
+    if opts.links_file.is_none() {
+        tracing::info!("no links file configured, /link pairings will be forgotten on restart");
+    } else {
+        tracing::info!("loaded {} links", links.len());
+    }
+    let links = Arc::new(Mutex::new(links));
+
+    // pending creds are sealed with this, and only opened to start a player
+    let creds_key = Arc::new(CredsKey::generate());
+
+    let key_ttl = Duration::from_secs(opts.key_ttl);
+    let stream_registry = Arc::new(RwLock::new(CredsRegistry::new(
+        key_ttl,
+        opts.max_pending_keys,
+    )));
+
+    let forwarder_sessions = Arc::new(Mutex::new(ForwarderSessions::default()));
+    let sessions = Arc::new(Mutex::new(SessionManager::new(opts.max_sessions)));
+
+    // evict keys nobody claimed so their creds don't sit in memory forever
+    let sweeper_jh = {
+        let registry = Arc::clone(&stream_registry);
+        let links = Arc::clone(&links);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(
+                key_ttl.clamp(Duration::from_secs(1), Duration::from_secs(30)),
+            );
+            loop {
+                interval.tick().await;
+                links.lock().unwrap().evict_expired();
+                let evicted = registry.write().unwrap().evict_expired();
+                if evicted > 0 {
+                    tracing::debug!(evicted, "evicted expired stream keys");
+                }
+            }
+        })
+    };
 
     tracing::info!("starting http server on port {}", opts.port);
     let rpc_server_jh = {
         let registry = Arc::clone(&stream_registry);
+        let forwarder_sessions = Arc::clone(&forwarder_sessions);
+        let sessions = Arc::clone(&sessions);
+        let links = Arc::clone(&links);
+        let creds_key = Arc::clone(&creds_key);
         tokio::spawn(async move {
-            let srv = receiver::server::Server::new(registry);
+            let srv = receiver::server::Server::new(
+                registry,
+                forwarder_sessions,
+                sessions,
+                links,
+                creds_key,
+                api_tokens,
+                api_rate_limit,
+            )
+            .require_sealed_creds(opts.require_sealed_creds);
             srv.run(opts.port).await?;
             Ok::<(), anyhow::Error>(())
         })
@@ -41,7 +163,15 @@ async fn main() -> Result<()> {
     tracing::info!("starting discord bot");
 
     let disc_jh = tokio::spawn(async move {
-        receiver::bot::run_bot(opts.bot_opts, stream_registry).await?;
+        receiver::bot::run_bot(
+            opts.bot_opts,
+            stream_registry,
+            forwarder_sessions,
+            sessions,
+            links,
+            creds_key,
+        )
+        .await?;
         Ok::<(), anyhow::Error>(())
     });
 
@@ -53,6 +183,9 @@ async fn main() -> Result<()> {
         _ = disc_jh => {
             tracing::info!("discord client exited");
         }
+        _ = sweeper_jh => {
+            tracing::info!("key sweeper exited");
+        }
         _ = common::util::ctrl_c() => {
             tracing::info!("received ctrl-c");
         }
@@ -0,0 +1,112 @@
+use std::io::{self, Read};
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+// the rest of the receiver records into these, and /metrics renders them
+
+pub static PENDING_KEYS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "spotify_remote_pending_keys",
+        "stream keys waiting for /play_spotify"
+    )
+    .unwrap()
+});
+
+pub static ACTIVE_SESSIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("spotify_remote_active_sessions", "guilds currently playing").unwrap()
+});
+
+// also what /readyz goes by
+pub static DISCORD_CONNECTED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "spotify_remote_discord_connected",
+        "1 while the discord gateway is connected"
+    )
+    .unwrap()
+});
+
+pub static PLAYER_START_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "spotify_remote_player_start_failures_total",
+        "players that failed to connect to spotify"
+    )
+    .unwrap()
+});
+
+pub static STREAM_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "spotify_remote_stream_duration_seconds",
+        "how long playback sessions lasted",
+        // a minute to eight hours
+        vec![60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 14400.0, 28800.0]
+    )
+    .unwrap()
+});
+
+pub static PCM_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "spotify_remote_pcm_bytes_total",
+        "bytes of pcm the players have delivered"
+    )
+    .unwrap()
+});
+
+pub static COMMANDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "spotify_remote_commands_total",
+        "slash commands and panel buttons used",
+        &["command"]
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "spotify_remote_rate_limited_total",
+        "requests turned away by rate limits and lockouts",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+// prometheus text format
+pub fn render() -> String {
+    // so everything shows up from the first scrape, not just what's been touched
+    Lazy::force(&PENDING_KEYS);
+    Lazy::force(&ACTIVE_SESSIONS);
+    Lazy::force(&DISCORD_CONNECTED);
+    Lazy::force(&PLAYER_START_FAILURES);
+    Lazy::force(&STREAM_DURATION);
+    Lazy::force(&PCM_BYTES);
+    Lazy::force(&COMMANDS);
+    Lazy::force(&RATE_LIMITED);
+
+    let mut buf = Vec::new();
+    if let Err(err) = TextEncoder::new().encode(&prometheus::gather(), &mut buf) {
+        tracing::warn!(?err, "failed to encode metrics");
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
+
+// counts what's read through it into PCM_BYTES
+pub struct CountingReader<R> {
+    inner: R,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        PCM_BYTES.inc_by(n as u64);
+        Ok(n)
+    }
+}
@@ -0,0 +1,240 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use player::{NowPlaying, PlayerEvent, TrackInfo};
+use poise::serenity_prelude::{ChannelId, CreateEmbed, Http, MessageId, UserId};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Playing,
+    Paused,
+    Ended,
+    // the session went away mid-track
+    Stopped,
+}
+
+impl Status {
+    fn describe(self) -> &'static str {
+        match self {
+            Status::Playing => "▶ playing",
+            Status::Paused => "⏸ paused",
+            Status::Ended => "⏹ finished",
+            Status::Stopped => "⏹ stopped",
+        }
+    }
+}
+
+// the message we're keeping up to date
+struct Announcement {
+    message_id: MessageId,
+    track: TrackInfo,
+    status: Status,
+}
+
+// posts an embed to the channel for every new track, and edits it when the track is paused, resumed or ends. runs
+// until the player goes away.
+pub async fn announce(
+    http: Arc<Http>,
+    channel_id: ChannelId,
+    key: String,
+    mut events: broadcast::Receiver<PlayerEvent>,
+) {
+    let mut current: Option<Announcement> = None;
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                tracing::debug!(?key, n, "missed player events");
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let (track_id, status) = match event {
+            PlayerEvent::TrackChanged { track, .. } => {
+                match channel_id
+                    .send_message(&http, |m| {
+                        m.embed(|e| track_embed(e, &key, &track, Status::Playing))
+                    })
+                    .await
+                {
+                    Ok(message) => {
+                        current = Some(Announcement {
+                            message_id: message.id,
+                            track,
+                            status: Status::Playing,
+                        })
+                    }
+                    Err(err) => tracing::warn!(?key, ?err, "failed to announce track"),
+                }
+                continue;
+            }
+            PlayerEvent::Paused { track_id, .. } => (track_id, Status::Paused),
+            PlayerEvent::Resumed { track_id, .. } => (track_id, Status::Playing),
+            PlayerEvent::Ended { track_id } => (track_id, Status::Ended),
+            // the embed doesn't show the position
+            PlayerEvent::Seeked { .. } => continue,
+        };
+        if let Some(announcement) = current.as_mut().filter(|a| a.track.id == track_id) {
+            update(&http, channel_id, &key, announcement, status).await;
+        }
+    }
+
+    if let Some(announcement) = current.as_mut() {
+        if matches!(announcement.status, Status::Playing | Status::Paused) {
+            update(&http, channel_id, &key, announcement, Status::Stopped).await;
+        }
+    }
+    tracing::debug!(?key, "done announcing");
+}
+
+async fn update(
+    http: &Http,
+    channel_id: ChannelId,
+    key: &str,
+    announcement: &mut Announcement,
+    status: Status,
+) {
+    if announcement.status == status {
+        return;
+    }
+    announcement.status = status;
+    let res = channel_id
+        .edit_message(http, announcement.message_id, |m| {
+            m.embed(|e| track_embed(e, key, &announcement.track, status))
+        })
+        .await;
+    if let Err(err) = res {
+        tracing::warn!(?key, ?err, "failed to update now playing message");
+    }
+}
+
+fn track_embed<'a>(
+    e: &'a mut CreateEmbed,
+    key: &str,
+    track: &TrackInfo,
+    status: Status,
+) -> &'a mut CreateEmbed {
+    e.title(&track.title).url(track.url());
+    if !track.artists.is_empty() {
+        e.description(track.artists.join(", "));
+    }
+    e.field("Length", format_duration(track.duration), true)
+        .footer(|f| f.text(format!("{} on {key}", status.describe())))
+}
+
+// what /nowplaying shows
+pub fn now_playing_embed<'a>(
+    e: &'a mut CreateEmbed,
+    key: &str,
+    device_name: &str,
+    started_by: UserId,
+    now_playing: &NowPlaying,
+) -> &'a mut CreateEmbed {
+    let track = &now_playing.track;
+    let status = if now_playing.paused {
+        Status::Paused
+    } else {
+        Status::Playing
+    };
+    let position = now_playing.position();
+    e.title(&track.title).url(track.url());
+    if !track.artists.is_empty() {
+        e.description(track.artists.join(", "));
+    }
+    e.field(
+        status.describe(),
+        format!(
+            "`{}` {} `{}`",
+            format_duration(position),
+            progress_bar(position, track.duration),
+            format_duration(track.duration)
+        ),
+        false,
+    )
+    .field(
+        "Stream",
+        format!("`{key}` from {device_name}, started by <@{started_by}>"),
+        false,
+    )
+}
+
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+fn progress_bar(position: Duration, duration: Duration) -> String {
+    let fraction = if duration.is_zero() {
+        0.0
+    } else {
+        (position.as_secs_f64() / duration.as_secs_f64()).min(1.0)
+    };
+    let filled = (fraction * (PROGRESS_BAR_WIDTH - 1) as f64).round() as usize;
+    format!(
+        "{}🔘{}",
+        "▬".repeat(filled),
+        "▬".repeat(PROGRESS_BAR_WIDTH - 1 - filled)
+    )
+}
+
+// m:ss, or h:mm:ss for long podcasts
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}:{m:02}:{s:02}")
+    } else {
+        format!("{m}:{s:02}")
+    }
+}
+
+// the inverse of format_duration, so also takes plain seconds
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let mut secs = 0u64;
+    let mut n = 0;
+    for part in s.trim().split(':') {
+        n += 1;
+        if n > 3 || part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let part: u64 = part.parse().ok()?;
+        // only the leading part may overflow its unit
+        if n > 1 && part >= 60 {
+            return None;
+        }
+        secs = secs.checked_mul(60)?.checked_add(part)?;
+    }
+    Some(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let duration = Duration::from_secs(3725);
+        assert_eq!(format_duration(duration), "1:02:05");
+        assert_eq!(format_duration(Duration::from_secs(65)), "1:05");
+
+        assert!(progress_bar(Duration::ZERO, duration).starts_with('🔘'));
+        assert!(progress_bar(duration, duration).ends_with('🔘'));
+        // past the end, eg when the next track is late to start
+        assert!(progress_bar(duration * 2, duration).ends_with('🔘'));
+        assert!(progress_bar(Duration::ZERO, Duration::ZERO).starts_with('🔘'));
+        assert_eq!(
+            progress_bar(duration / 2, duration).chars().count(),
+            PROGRESS_BAR_WIDTH
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("1:23"), Some(Duration::from_secs(83)));
+        assert_eq!(parse_duration("83"), Some(Duration::from_secs(83)));
+        assert_eq!(parse_duration("1:02:05"), Some(Duration::from_secs(3725)));
+        assert_eq!(parse_duration(" 0:05 "), Some(Duration::from_secs(5)));
+        for bad in ["", ":", "1:", "1:60", "1:2:3:4", "-1", "1.5", "a:bc"] {
+            assert_eq!(parse_duration(bad), None, "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::{Arc, Mutex};
+
+use player::{NowPlaying, PlayerEvent};
+use poise::serenity_prelude::{ButtonStyle, ChannelId, CreateComponents, GuildId, Http, MessageId};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::now_playing::format_duration;
+use crate::sessions::SessionManager;
+
+// how much the volume buttons move the volume, in percent
+pub const VOLUME_STEP: u8 = 10;
+const CUSTOM_ID_PREFIX: &str = "panel";
+
+// the panel's buttons
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Previous,
+    PlayPause,
+    Next,
+    VolumeDown,
+    VolumeUp,
+    Stop,
+}
+
+impl Action {
+    const ALL: [Action; 6] = [
+        Action::Previous,
+        Action::PlayPause,
+        Action::Next,
+        Action::VolumeDown,
+        Action::VolumeUp,
+        Action::Stop,
+    ];
+
+    pub fn id(self) -> &'static str {
+        match self {
+            Action::Previous => "previous",
+            Action::PlayPause => "play_pause",
+            Action::Next => "next",
+            Action::VolumeDown => "volume_down",
+            Action::VolumeUp => "volume_up",
+            Action::Stop => "stop",
+        }
+    }
+
+    fn emoji(self) -> char {
+        match self {
+            Action::Previous => '⏮',
+            Action::PlayPause => '⏯',
+            Action::Next => '⏭',
+            Action::VolumeDown => '🔉',
+            Action::VolumeUp => '🔊',
+            Action::Stop => '⏹',
+        }
+    }
+}
+
+// buttons carry the stream key, so presses on the panel of a stream that has since been replaced can be told apart
+pub fn custom_id(action: Action, key: &str) -> String {
+    format!("{CUSTOM_ID_PREFIX}:{}:{key}", action.id())
+}
+
+pub fn parse_custom_id(custom_id: &str) -> Option<(Action, &str)> {
+    let mut parts = custom_id.splitn(3, ':');
+    if parts.next()? != CUSTOM_ID_PREFIX {
+        return None;
+    }
+    let id = parts.next()?;
+    let key = parts.next()?;
+    let action = Action::ALL.into_iter().find(|action| action.id() == id)?;
+    Some((action, key))
+}
+
+// the panel's text. None means the stream is over
+pub fn content(key: &str, state: Option<(Option<&NowPlaying>, u8)>) -> String {
+    match state {
+        None => format!("⏹ `{key}` stopped"),
+        Some((None, volume)) => {
+            format!("Waiting for spotify to play something on `{key}` · 🔊 {volume}%")
+        }
+        Some((Some(now_playing), volume)) => {
+            let track = &now_playing.track;
+            let status = if now_playing.paused { "⏸" } else { "▶" };
+            let by = if track.artists.is_empty() {
+                String::new()
+            } else {
+                format!(" by {}", track.artists.join(", "))
+            };
+            format!(
+                "{status} **{}**{by} ({}) on `{key}` · 🔊 {volume}%",
+                track.title,
+                format_duration(track.duration)
+            )
+        }
+    }
+}
+
+pub fn components<'a>(c: &'a mut CreateComponents, key: &str) -> &'a mut CreateComponents {
+    c.create_action_row(|row| {
+        for action in Action::ALL {
+            row.create_button(|b| {
+                b.custom_id(custom_id(action, key))
+                    .emoji(action.emoji())
+                    .style(if action == Action::Stop {
+                        ButtonStyle::Danger
+                    } else {
+                        ButtonStyle::Secondary
+                    })
+            });
+        }
+        row
+    })
+}
+
+// what's playing and the volume, if the guild is still playing this key
+pub fn snapshot(
+    sessions: &Mutex<SessionManager>,
+    guild_id: GuildId,
+    key: &str,
+) -> Option<(Option<NowPlaying>, u8)> {
+    let sessions = sessions.lock().unwrap();
+    let session = sessions
+        .get(guild_id)
+        .filter(|session| session.key == key)?;
+    Some((
+        session.spotify().now_playing(),
+        session.spotify().volume_percent(),
+    ))
+}
+
+// posts the panel and keeps it up to date until the player goes away, then takes the buttons off it
+pub async fn run(
+    http: Arc<Http>,
+    channel_id: ChannelId,
+    guild_id: GuildId,
+    key: String,
+    sessions: Arc<Mutex<SessionManager>>,
+    mut events: broadcast::Receiver<PlayerEvent>,
+) {
+    let text = render(&sessions, guild_id, &key);
+    let message_id = match channel_id
+        .send_message(&http, |m| {
+            m.content(text).components(|c| components(c, &key))
+        })
+        .await
+    {
+        Ok(message) => message.id,
+        Err(err) => {
+            tracing::warn!(?key, ?err, "failed to post control panel");
+            return;
+        }
+    };
+
+    loop {
+        match events.recv().await {
+            // the position isn't shown, so seeks don't change anything
+            Ok(PlayerEvent::Seeked { .. }) => continue,
+            // render from the session rather than the event, so we catch up on anything we missed
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+        let text = render(&sessions, guild_id, &key);
+        update(&http, channel_id, message_id, &key, text, true).await;
+    }
+
+    update(
+        &http,
+        channel_id,
+        message_id,
+        &key,
+        content(&key, None),
+        false,
+    )
+    .await;
+    tracing::debug!(?key, "control panel closed");
+}
+
+fn render(sessions: &Mutex<SessionManager>, guild_id: GuildId, key: &str) -> String {
+    let state = snapshot(sessions, guild_id, key);
+    content(
+        key,
+        state
+            .as_ref()
+            .map(|(now_playing, volume)| (now_playing.as_ref(), *volume)),
+    )
+}
+
+async fn update(
+    http: &Http,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    key: &str,
+    text: String,
+    buttons: bool,
+) {
+    let res = channel_id
+        .edit_message(http, message_id, |m| {
+            m.content(text);
+            if buttons {
+                m.components(|c| components(c, key))
+            } else {
+                m.components(|c| c)
+            }
+        })
+        .await;
+    if let Err(err) = res {
+        tracing::warn!(?key, ?err, "failed to update control panel");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_id() {
+        for action in Action::ALL {
+            let id = custom_id(action, "brave-otter-42");
+            assert_eq!(parse_custom_id(&id), Some((action, "brave-otter-42")));
+        }
+        assert_eq!(parse_custom_id("panel:stop"), None);
+        assert_eq!(parse_custom_id("panel:dance:brave-otter-42"), None);
+        assert_eq!(parse_custom_id("other:stop:brave-otter-42"), None);
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use protocol::ErrorCode;
+
+use crate::api_error::ApiFailure;
+use crate::metrics;
+
+// past this many tracked keys, forget the ones that have been quiet long enough not to matter
+const PRUNE_THRESHOLD: usize = 1024;
+
+// a token bucket per key: up to `per_minute` in a burst, refilling at `per_minute` a minute. 0 means no limit
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    per_minute: u32,
+    buckets: HashMap<K, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // takes a token, or says how long until there is one
+    pub fn check(&mut self, key: K, now: Instant) -> Result<(), Duration> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        if self.buckets.len() > PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        let capacity = self.per_minute as f64;
+        let rate = capacity / 60.0;
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+
+    // full buckets are no different from missing ones
+    fn prune(&mut self, now: Instant) {
+        let capacity = self.per_minute as f64;
+        let rate = capacity / 60.0;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate < capacity
+        });
+    }
+}
+
+// locks a key out for a while once it fails too often, eg guessing stream keys. failures count for as long as the
+// lockout would last. 0 max failures means no lockouts
+#[derive(Debug)]
+pub struct Lockout<K> {
+    max_failures: u32,
+    duration: Duration,
+    entries: HashMap<K, Failures>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    first_at: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl<K: Hash + Eq> Lockout<K> {
+    pub fn new(max_failures: u32, duration: Duration) -> Self {
+        Self {
+            max_failures,
+            duration,
+            entries: HashMap::new(),
+        }
+    }
+
+    // how much longer the key is locked out for, if it is
+    pub fn locked(&self, key: &K, now: Instant) -> Option<Duration> {
+        self.entries
+            .get(key)
+            .and_then(|failures| failures.locked_until)
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    // counts a failure, returning how long the key is now locked out for if that was one too many
+    pub fn fail(&mut self, key: K, now: Instant) -> Option<Duration> {
+        if self.max_failures == 0 {
+            return None;
+        }
+        if self.entries.len() > PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        let failures = self.entries.entry(key).or_insert(Failures {
+            count: 0,
+            first_at: now,
+            locked_until: None,
+        });
+        if now.saturating_duration_since(failures.first_at) >= self.duration {
+            *failures = Failures {
+                count: 0,
+                first_at: now,
+                locked_until: None,
+            };
+        }
+        failures.count += 1;
+        if failures.count >= self.max_failures {
+            // the window starts over once the lockout is up
+            failures.count = 0;
+            failures.first_at = now + self.duration;
+            failures.locked_until = Some(now + self.duration);
+            return Some(self.duration);
+        }
+        None
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let duration = self.duration;
+        self.entries.retain(|_, failures| {
+            now.saturating_duration_since(failures.first_at) < duration
+                || failures.locked_until.is_some_and(|until| until > now)
+        });
+    }
+}
+
+// the per-ip limit on the api
+#[derive(Debug, Clone)]
+pub struct ApiRateLimit {
+    limiter: Arc<Mutex<RateLimiter<IpAddr>>>,
+    // behind a reverse proxy every request comes from the proxy, so go by the address it says it's forwarding for
+    trust_forwarded_for: bool,
+}
+
+impl ApiRateLimit {
+    pub fn new(per_minute: u32, trust_forwarded_for: bool) -> Self {
+        Self {
+            limiter: Arc::new(Mutex::new(RateLimiter::new(per_minute))),
+            trust_forwarded_for,
+        }
+    }
+
+    fn client_ip(&self, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+        if self.trust_forwarded_for {
+            // the proxy appends the address it saw, and anything before that is the client's to make up
+            let forwarded = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|h| h.to_str().ok())
+                .flat_map(|h| h.split(','))
+                .last()
+                .and_then(|ip| ip.trim().parse().ok());
+            if let Some(ip) = forwarded {
+                return ip;
+            }
+        }
+        peer.ip()
+    }
+}
+
+pub async fn limit_by_ip<B>(
+    State(limit): State<ApiRateLimit>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let ip = limit.client_ip(peer, req.headers());
+    let res = limit.limiter.lock().unwrap().check(ip, Instant::now());
+    if let Err(retry_after) = res {
+        tracing::debug!(?ip, ?retry_after, uri = ?req.uri(), "rate limited api request");
+        metrics::RATE_LIMITED.with_label_values(&["api"]).inc();
+        return ApiFailure::new(
+            ErrorCode::RateLimited,
+            "too many requests from this address",
+        )
+        .retry_after(retry_after)
+        .into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut limiter = RateLimiter::new(3);
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", at(0)), Ok(()));
+        }
+        assert_eq!(limiter.check("a", at(0)), Err(Duration::from_secs(20)));
+        // other keys have their own bucket
+        assert_eq!(limiter.check("b", at(0)), Ok(()));
+        assert!(limiter.check("a", at(10)).is_err());
+        assert_eq!(limiter.check("a", at(20)), Ok(()));
+        assert!(limiter.check("a", at(20)).is_err());
+        // refills up to the burst and no further
+        for _ in 0..3 {
+            assert_eq!(limiter.check("a", at(600)), Ok(()));
+        }
+        assert!(limiter.check("a", at(600)).is_err());
+
+        let mut unlimited = RateLimiter::new(0);
+        for _ in 0..100 {
+            assert_eq!(unlimited.check("a", at(0)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_lockout() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let minute = Duration::from_secs(60);
+        let mut lockout = Lockout::new(3, minute);
+
+        assert_eq!(lockout.fail("a", at(0)), None);
+        assert_eq!(lockout.fail("a", at(10)), None);
+        assert_eq!(lockout.locked(&"a", at(10)), None);
+        assert_eq!(lockout.fail("a", at(20)), Some(minute));
+        assert_eq!(lockout.locked(&"a", at(50)), Some(Duration::from_secs(30)));
+        assert_eq!(lockout.locked(&"b", at(50)), None);
+        assert_eq!(lockout.locked(&"a", at(80)), None);
+
+        // failures spread out further than the lockout don't add up
+        assert_eq!(lockout.fail("a", at(100)), None);
+        assert_eq!(lockout.fail("a", at(150)), None);
+        assert_eq!(lockout.fail("a", at(200)), None);
+        assert_eq!(lockout.locked(&"a", at(200)), None);
+
+        let mut disabled = Lockout::new(0, minute);
+        for _ in 0..100 {
+            assert_eq!(disabled.fail("a", at(0)), None);
+        }
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let peer: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.2.3.4, 5.6.7.8"),
+        );
+        assert_eq!(
+            ApiRateLimit::new(1, false).client_ip(peer, &headers),
+            peer.ip()
+        );
+        assert_eq!(
+            ApiRateLimit::new(1, true).client_ip(peer, &headers),
+            "5.6.7.8".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            ApiRateLimit::new(1, true).client_ip(peer, &HeaderMap::new()),
+            peer.ip()
+        );
+    }
+}
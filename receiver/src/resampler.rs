@@ -0,0 +1,223 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::Result;
+use rubato::{FftFixedInOut, Resampler as _};
+use songbird::input::reader::MediaSource;
+
+// what the player writes
+pub const INPUT_SAMPLE_RATE: usize = 44_100;
+// what discord wants
+pub const OUTPUT_SAMPLE_RATE: usize = 48_000;
+const CHANNELS: usize = 2;
+const INPUT_FRAME_BYTES: usize = CHANNELS * std::mem::size_of::<i16>();
+// ~23ms of audio
+const CHUNK_FRAMES: usize = 1024;
+
+// wraps the player's stdout (interleaved s16le stereo at 44.1khz) and produces interleaved f32le stereo at 48khz,
+// which is what songbird's `Codec::FloatPcm` expects
+pub struct Resampler<R> {
+    inner: R,
+    resampler: FftFixedInOut<f32>,
+    // bytes read from inner that haven't been resampled yet
+    pending: Vec<u8>,
+    output: Vec<u8>,
+    output_pos: usize,
+    // output frames still to drop to make up for the resampler's delay
+    delay: usize,
+    frames_in: u64,
+    frames_out: u64,
+    eof: bool,
+}
+
+impl<R: Read> Resampler<R> {
+    pub fn new(inner: R) -> Result<Self> {
+        let resampler = FftFixedInOut::new(
+            INPUT_SAMPLE_RATE,
+            OUTPUT_SAMPLE_RATE,
+            CHUNK_FRAMES,
+            CHANNELS,
+        )?;
+        Ok(Self {
+            inner,
+            delay: resampler.output_delay(),
+            resampler,
+            pending: Vec::new(),
+            output: Vec::new(),
+            output_pos: 0,
+            frames_in: 0,
+            frames_out: 0,
+            eof: false,
+        })
+    }
+
+    // resample the next chunk of input into `output`
+    fn fill(&mut self) -> io::Result<()> {
+        let chunk_bytes = self.resampler.input_frames_next() * INPUT_FRAME_BYTES;
+        while self.pending.len() < chunk_bytes {
+            let start = self.pending.len();
+            self.pending.resize(chunk_bytes, 0);
+            let res = loop {
+                match self.inner.read(&mut self.pending[start..]) {
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    res => break res,
+                }
+            };
+            let n = res.inspect_err(|_| self.pending.truncate(start))?;
+            self.pending.truncate(start + n);
+            if n == 0 {
+                return self.finish();
+            }
+        }
+
+        let wave_in = deinterleave(&self.pending[..chunk_bytes]);
+        self.pending.drain(..chunk_bytes);
+        self.frames_in += (chunk_bytes / INPUT_FRAME_BYTES) as u64;
+        let wave_out = self
+            .resampler
+            .process(&wave_in, None)
+            .map_err(io::Error::other)?;
+        self.push_output(&wave_out, None);
+        Ok(())
+    }
+
+    // input is done: resample what's left and flush the resampler's delay line, so the output
+    // is exactly as long as the input was
+    fn finish(&mut self) -> io::Result<()> {
+        self.eof = true;
+        // a trailing partial frame can't be played anyway
+        let whole_bytes = self.pending.len() / INPUT_FRAME_BYTES * INPUT_FRAME_BYTES;
+        let mut rest = Some(deinterleave(&self.pending[..whole_bytes])).filter(|_| whole_bytes > 0);
+        self.frames_in += (whole_bytes / INPUT_FRAME_BYTES) as u64;
+        self.pending.clear();
+
+        let expected =
+            (self.frames_in * OUTPUT_SAMPLE_RATE as u64).div_ceil(INPUT_SAMPLE_RATE as u64);
+        while self.frames_out < expected {
+            let wave_out = self
+                .resampler
+                .process_partial(rest.take().as_deref(), None)
+                .map_err(io::Error::other)?;
+            self.push_output(&wave_out, Some(expected));
+        }
+        Ok(())
+    }
+
+    fn push_output(&mut self, wave_out: &[Vec<f32>], limit: Option<u64>) {
+        for i in 0..wave_out[0].len() {
+            if self.delay > 0 {
+                self.delay -= 1;
+                continue;
+            }
+            if limit.is_some_and(|limit| self.frames_out >= limit) {
+                break;
+            }
+            for channel in wave_out {
+                self.output.extend_from_slice(&channel[i].to_le_bytes());
+            }
+            self.frames_out += 1;
+        }
+    }
+}
+
+impl<R: Read> Read for Resampler<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.output.len() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.output.clear();
+            self.output_pos = 0;
+            self.fill()?;
+        }
+        let n = buf.len().min(self.output.len() - self.output_pos);
+        buf[..n].copy_from_slice(&self.output[self.output_pos..self.output_pos + n]);
+        self.output_pos += n;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for Resampler<R> {
+    fn seek(&mut self, _pos: SeekFrom) -> io::Result<u64> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl<R: Read + Send + Sync> MediaSource for Resampler<R> {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+fn deinterleave(bytes: &[u8]) -> Vec<Vec<f32>> {
+    let mut wave: Vec<Vec<f32>> = (0..CHANNELS)
+        .map(|_| Vec::with_capacity(bytes.len() / INPUT_FRAME_BYTES))
+        .collect();
+    for frame in bytes.chunks_exact(INPUT_FRAME_BYTES) {
+        for (channel, sample) in wave.iter_mut().zip(frame.chunks_exact(2)) {
+            let sample = i16::from_le_bytes([sample[0], sample[1]]);
+            channel.push(sample as f32 / i16::MAX as f32);
+        }
+    }
+    wave
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample_sine() {
+        const FREQ: f32 = 1000.0;
+        const SECONDS: usize = 2;
+
+        let mut input = Vec::new();
+        for i in 0..INPUT_SAMPLE_RATE * SECONDS {
+            let t = i as f32 / INPUT_SAMPLE_RATE as f32;
+            let sample =
+                ((2.0 * std::f32::consts::PI * FREQ * t).sin() * 0.5 * i16::MAX as f32) as i16;
+            // same signal on both channels
+            input.extend_from_slice(&sample.to_le_bytes());
+            input.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        let mut output = Vec::new();
+        Resampler::new(io::Cursor::new(input))
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+
+        let samples: Vec<f32> = output
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(samples.len(), OUTPUT_SAMPLE_RATE * SECONDS * CHANNELS);
+
+        let left: Vec<f32> = samples.iter().step_by(CHANNELS).copied().collect();
+        let right: Vec<f32> = samples.iter().skip(1).step_by(CHANNELS).copied().collect();
+        assert_eq!(left, right);
+
+        // count rising zero crossings in the middle second, away from the edges
+        let middle = &left[OUTPUT_SAMPLE_RATE / 2..OUTPUT_SAMPLE_RATE * 3 / 2];
+        let crossings = middle
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        assert!(
+            (crossings as f32 - FREQ).abs() <= 2.0,
+            "expected ~{} Hz, got {} rising zero crossings",
+            FREQ,
+            crossings
+        );
+
+        let peak = middle.iter().fold(0f32, |acc, s| acc.max(s.abs()));
+        assert!(
+            (peak - 0.5).abs() < 0.02,
+            "unexpected peak amplitude {}",
+            peak
+        );
+    }
+}
@@ -1,49 +1,525 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
 
 use anyhow::Result;
-use axum::http::StatusCode;
-use protocol::ForwardCreds;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use protocol::{
+    Capability, CredsKey, Envelope, ErrorCode, ForwardCreds, ForwardCredsResponse, ForwardedCreds,
+    KeyStatus, LinkRequest, LinkResponse, LinkStatus, LinkStatusRequest, PublicKeyResponse,
+    SessionsResponse, TrackSummary, VersionInfo, VoiceLocation,
+};
 
-use crate::creds_registry::CredsRegistry;
+use crate::api_error::ApiFailure;
+use crate::auth::{self, ApiTokens};
+use crate::creds_registry::{CredsRegistry, InsertError};
+use crate::forwarder_sessions::{ConnectError, ForwarderSessions};
+use crate::links::{self, LinkRegistry};
+use crate::metrics;
+use crate::rate_limit::{self, ApiRateLimit};
+use crate::sessions::SessionManager;
 use common::util;
 
 pub struct Server {
     registry: Arc<RwLock<CredsRegistry>>,
+    forwarder_sessions: Arc<Mutex<ForwarderSessions>>,
+    sessions: Arc<Mutex<SessionManager>>,
+    links: Arc<Mutex<LinkRegistry>>,
+    creds_key: Arc<CredsKey>,
+    require_sealed_creds: bool,
+    api_tokens: Arc<ApiTokens>,
+    api_rate_limit: ApiRateLimit,
+}
+
+#[derive(Clone)]
+struct AppState {
+    registry: Arc<RwLock<CredsRegistry>>,
+    forwarder_sessions: Arc<Mutex<ForwarderSessions>>,
+    sessions: Arc<Mutex<SessionManager>>,
+    links: Arc<Mutex<LinkRegistry>>,
+    creds_key: Arc<CredsKey>,
+    require_sealed_creds: bool,
 }
 
 impl Server {
-    pub fn new(registry: Arc<RwLock<CredsRegistry>>) -> Self {
-        Self { registry }
+    pub fn new(
+        registry: Arc<RwLock<CredsRegistry>>,
+        forwarder_sessions: Arc<Mutex<ForwarderSessions>>,
+        sessions: Arc<Mutex<SessionManager>>,
+        links: Arc<Mutex<LinkRegistry>>,
+        creds_key: Arc<CredsKey>,
+        api_tokens: ApiTokens,
+        api_rate_limit: ApiRateLimit,
+    ) -> Self {
+        Self {
+            registry,
+            forwarder_sessions,
+            sessions,
+            links,
+            creds_key,
+            require_sealed_creds: false,
+            api_tokens: Arc::new(api_tokens),
+            api_rate_limit,
+        }
+    }
+
+    // turn away forwarders that send creds in the clear
+    pub fn require_sealed_creds(mut self, require: bool) -> Self {
+        self.require_sealed_creds = require;
+        self
     }
 
     pub async fn run(self, port: u16) -> Result<()> {
-        use axum::routing::post;
-        use axum::Json;
+        use axum::middleware;
+        use axum::routing::{get, post};
         use axum::Router;
 
-        let app = Router::new().route(
-            "/api/forward_creds",
-            post(|Json(payload): Json<ForwardCreds>| async move {
-                tracing::debug!(?payload.key, ?payload.creds.username, ?payload.device_name, "got forwarded creds");
-                let mut reg = self.registry.write().unwrap();
-                match reg.insert(payload) {
-                    true => StatusCode::OK,
-                    false => StatusCode::CONFLICT,
-                }
-            }),
-        );
+        let state = AppState {
+            registry: self.registry,
+            forwarder_sessions: self.forwarder_sessions,
+            sessions: self.sessions,
+            links: self.links,
+            creds_key: self.creds_key,
+            require_sealed_creds: self.require_sealed_creds,
+        };
 
-        // TODO: maybe keep a connection with heartbeat open from forwarder, kill the player if it dies. to better simulate a real local device
+        let app = Router::new()
+            .route("/api/public_key", get(public_key))
+            .route("/api/forward_creds", post(forward_creds))
+            .route("/api/session/:key", get(forwarder_session))
+            .route("/api/sessions", get(list_sessions))
+            .route("/api/key/:key/status", get(key_status))
+            .route("/api/link", post(start_link))
+            .route("/api/link/status", post(link_status))
+            .route_layer(middleware::from_fn_with_state(
+                self.api_tokens,
+                auth::require_token,
+            ))
+            // so forwarders can tell whether they can talk to us before worrying about tokens
+            .route("/api/version", get(version))
+            // outside the auth check, so guessing api tokens counts too
+            .route_layer(middleware::from_fn_with_state(
+                self.api_rate_limit,
+                rate_limit::limit_by_ip,
+            ))
+            // for probes and scrapers, so not behind the api tokens
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
+            .route("/metrics", get(render_metrics))
+            .with_state(state);
 
         let addr = SocketAddr::from(([0, 0, 0, 0], port));
         tracing::debug!("listening on {}", addr);
         axum::Server::bind(&addr)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .with_graceful_shutdown(util::ctrl_c())
             .await?;
 
         Ok(())
     }
 }
+
+async fn forward_creds(
+    State(state): State<AppState>,
+    envelope: Result<Json<Envelope<ForwardCreds>>, JsonRejection>,
+) -> Result<Json<ForwardCredsResponse>, ApiFailure> {
+    let Json(envelope) = envelope.map_err(invalid_request)?;
+    let info = version_info();
+    if !info.supports(envelope.protocol_version) {
+        tracing::debug!(
+            protocol_version = envelope.protocol_version,
+            forwarder_version = ?envelope.forwarder_version,
+            "rejected incompatible forwarder"
+        );
+        return Err(ApiFailure::new(
+            ErrorCode::IncompatibleVersion,
+            format!(
+                "this receiver speaks protocol versions {} to {}, not {}",
+                info.min_protocol_version, info.protocol_version, envelope.protocol_version
+            ),
+        ));
+    }
+    let mut payload = envelope.body;
+    tracing::debug!(?payload.key, ?payload.creds, ?payload.device_name, forwarder_version = ?envelope.forwarder_version, "got forwarded creds");
+    if let Err(err) = protocol::validate_key(&payload.key) {
+        tracing::debug!(?payload.key, ?err, "rejected invalid key");
+        return Err(ApiFailure::new(ErrorCode::InvalidKey, err));
+    }
+    // forwarders from before sealing. keep their creds sealed like everyone else's
+    if let ForwardedCreds::Plain(creds) = &payload.creds {
+        if state.require_sealed_creds {
+            tracing::debug!(?payload.key, "rejected unsealed creds");
+            return Err(ApiFailure::new(
+                ErrorCode::InvalidRequest,
+                "this receiver only accepts sealed credentials",
+            ));
+        }
+        let sealed = state.creds_key.seal(creds).map_err(|err| {
+            tracing::warn!(?err, "failed to seal creds");
+            ApiFailure::new(ErrorCode::Internal, "failed to seal credentials")
+        })?;
+        payload.creds = ForwardedCreds::Sealed(sealed);
+    }
+    // a linked forwarder's keys belong to whoever linked it
+    let linked_user = payload
+        .link_token
+        .take()
+        .and_then(|token| state.links.lock().unwrap().user_for(&token));
+    if linked_user.is_some() {
+        payload.discord_user = linked_user;
+    }
+    let mut reg = state.registry.write().unwrap();
+    let mut forwarder_sessions = state.forwarder_sessions.lock().unwrap();
+    let key = payload.key.clone();
+    // another forwarder is still around for the key, eg it's playing
+    if !forwarder_sessions.is_free(&key) {
+        return Err(ApiFailure::new(
+            ErrorCode::KeyInUse,
+            format!("the key {key} is already in use"),
+        ));
+    }
+    match reg.insert(payload) {
+        Ok(()) => Ok(Json(ForwardCredsResponse {
+            expires_in_secs: reg.ttl().as_secs(),
+            linked_user,
+            session_secret: forwarder_sessions.register(&key),
+        })),
+        Err(InsertError::InUse) => Err(ApiFailure::new(
+            ErrorCode::KeyInUse,
+            format!("the key {key} is already waiting to be claimed"),
+        )),
+        Err(InsertError::Full) => {
+            tracing::warn!("too many pending keys, turning away forwarded creds");
+            Err(ApiFailure::new(
+                ErrorCode::AtCapacity,
+                "too many keys are waiting to be claimed",
+            )
+            // about when the oldest ones will have been claimed or expired
+            .retry_after(Duration::from_secs(60).min(reg.ttl())))
+        }
+    }
+}
+
+async fn version() -> Json<VersionInfo> {
+    Json(version_info())
+}
+
+fn version_info() -> VersionInfo {
+    VersionInfo {
+        protocol_version: protocol::PROTOCOL_VERSION,
+        min_protocol_version: protocol::MIN_PROTOCOL_VERSION,
+        receiver_version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: Capability::ALL.to_vec(),
+    }
+}
+
+// for forwarders to seal creds with
+async fn public_key(State(state): State<AppState>) -> Json<PublicKeyResponse> {
+    Json(PublicKeyResponse {
+        public_key: state.creds_key.public_key(),
+    })
+}
+
+// a forwarder wants pairing with a discord user, who'll confirm with /link
+async fn start_link(
+    State(state): State<AppState>,
+    payload: Result<Json<LinkRequest>, JsonRejection>,
+) -> Result<Json<LinkResponse>, ApiFailure> {
+    let Json(payload) = payload.map_err(invalid_request)?;
+    let (code, token) = state.links.lock().unwrap().start(payload.device_name);
+    tracing::debug!(?code, "started link");
+    Ok(Json(LinkResponse {
+        code,
+        token,
+        expires_in_secs: links::CODE_TTL.as_secs(),
+    }))
+}
+
+async fn link_status(
+    State(state): State<AppState>,
+    payload: Result<Json<LinkStatusRequest>, JsonRejection>,
+) -> Result<Json<LinkStatus>, ApiFailure> {
+    let Json(payload) = payload.map_err(invalid_request)?;
+    Ok(Json(state.links.lock().unwrap().status(&payload.token)))
+}
+
+// a body that didn't parse, as an api error rather than axum's plain text
+fn invalid_request(rejection: JsonRejection) -> ApiFailure {
+    tracing::debug!(?rejection, "rejected unparseable request");
+    ApiFailure::new(ErrorCode::InvalidRequest, rejection.body_text())
+}
+
+// the process is up and serving http
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+// we can actually play: the bot is connected to discord. the player runs in-process, so there's nothing else to
+// check for it.
+async fn readyz() -> (StatusCode, &'static str) {
+    if metrics::DISCORD_CONNECTED.get() == 1 {
+        (StatusCode::OK, "ok")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "discord gateway not connected",
+        )
+    }
+}
+
+async fn render_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    metrics::PENDING_KEYS.set(state.registry.read().unwrap().pending() as i64);
+    metrics::ACTIVE_SESSIONS.set(state.sessions.lock().unwrap().len() as i64);
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+async fn list_sessions(State(state): State<AppState>) -> Json<SessionsResponse> {
+    let sessions = state.sessions.lock().unwrap();
+    Json(SessionsResponse {
+        max_sessions: sessions.max_sessions(),
+        sessions: sessions.list().into_iter().map(|(_, info)| info).collect(),
+    })
+}
+
+// lets a forwarder see whether its key got used
+async fn key_status(State(state): State<AppState>, Path(key): Path<String>) -> Json<KeyStatus> {
+    let sessions = state.sessions.lock().unwrap();
+    let registry = state.registry.read().unwrap();
+    let facts = KeyFacts {
+        playing: sessions
+            .find_by_key(&key)
+            .map(|session| (session.location.clone(), session.track_summary())),
+        pending: registry.expires_in(&key),
+        stopped: sessions.is_stopped(&key),
+        // still joining the voice channel and connecting to spotify, or being restarted by /reset
+        claimed_at: sessions
+            .restarting(&key)
+            .or_else(|| registry.claimed_at(&key))
+            .cloned(),
+        expired: registry.is_expired_key(&key),
+    };
+    Json(facts.status())
+}
+
+// what the sessions and the registry know about a key
+#[derive(Debug, Default)]
+struct KeyFacts {
+    playing: Option<(VoiceLocation, Option<TrackSummary>)>,
+    pending: Option<Duration>,
+    stopped: bool,
+    claimed_at: Option<VoiceLocation>,
+    expired: bool,
+}
+
+impl KeyFacts {
+    // the most current of them wins
+    fn status(self) -> KeyStatus {
+        if let Some((location, track)) = self.playing {
+            match track {
+                Some(track) => KeyStatus::Playing { location, track },
+                None => KeyStatus::Claimed { location },
+            }
+        } else if let Some(expires_in) = self.pending {
+            KeyStatus::Pending {
+                expires_in_secs: expires_in.as_secs(),
+            }
+        } else if self.stopped {
+            KeyStatus::Stopped
+        } else if let Some(location) = self.claimed_at {
+            KeyStatus::Claimed { location }
+        } else if self.expired {
+            KeyStatus::Expired
+        } else {
+            KeyStatus::Unknown
+        }
+    }
+}
+
+// the forwarder keeps this open for as long as its device is alive, so we can stop playback when it goes away
+// only the forwarder that posted the key may open it, with the secret it got back, and only once at a time
+async fn forwarder_session(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Result<Response, ApiFailure> {
+    let secret = headers
+        .get(protocol::SESSION_SECRET_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let res = state
+        .forwarder_sessions
+        .lock()
+        .unwrap()
+        .connect(&key, secret);
+    match res {
+        Ok(()) => {}
+        Err(ConnectError::Unknown) => {
+            tracing::debug!(?key, "rejected forwarder session with the wrong secret");
+            return Err(ApiFailure::new(
+                ErrorCode::Forbidden,
+                "no forwarded creds for this key and secret",
+            ));
+        }
+        Err(ConnectError::AlreadyConnected) => {
+            tracing::debug!(?key, "rejected second forwarder session");
+            return Err(ApiFailure::new(
+                ErrorCode::KeyInUse,
+                "the key's forwarder is already connected",
+            ));
+        }
+    }
+    let connection = Connection {
+        sessions: Arc::clone(&state.forwarder_sessions),
+        key,
+        closed: false,
+    };
+    Ok(ws.on_upgrade(move |socket| handle_forwarder_session(socket, connection, state)))
+}
+
+// a connected forwarder session. if it's dropped without the forwarder saying goodbye, eg the upgrade failed or
+// the network did, the forwarder gets a while to reconnect
+struct Connection {
+    sessions: Arc<Mutex<ForwarderSessions>>,
+    key: String,
+    closed: bool,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if !self.closed {
+            self.sessions.lock().unwrap().lost(&self.key);
+        }
+    }
+}
+
+async fn handle_forwarder_session(
+    mut socket: WebSocket,
+    mut connection: Connection,
+    state: AppState,
+) {
+    let key = connection.key.clone();
+    tracing::debug!(?key, "forwarder session opened");
+
+    loop {
+        match tokio::time::timeout(protocol::HEARTBEAT_TIMEOUT, socket.recv()).await {
+            Err(_) => {
+                tracing::info!(?key, "forwarder session timed out");
+                return;
+            }
+            Ok(None) | Ok(Some(Ok(Message::Close(_)))) => {
+                tracing::info!(?key, "forwarder session closed");
+                break;
+            }
+            Ok(Some(Err(err))) => {
+                tracing::info!(?key, ?err, "forwarder session failed");
+                return;
+            }
+            Ok(Some(Ok(_))) => {
+                tracing::trace!(?key, "forwarder heartbeat");
+            }
+        }
+    }
+
+    connection.closed = true;
+    state.forwarder_sessions.lock().unwrap().disconnected(&key);
+    // if nobody claimed the key yet there's no point keeping the creds around
+    if state.registry.write().unwrap().discard(&key).is_some() {
+        tracing::debug!(?key, "discarded unclaimed creds for departed forwarder");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_status() {
+        let location = VoiceLocation {
+            guild_id: 1,
+            guild_name: "guild".to_string(),
+            channel_id: 2,
+            channel_name: "channel".to_string(),
+        };
+        let track = TrackSummary {
+            id: "id".to_string(),
+            title: "song".to_string(),
+            artists: vec![],
+            paused: false,
+        };
+        let claimed = KeyStatus::Claimed {
+            location: location.clone(),
+        };
+
+        assert_eq!(KeyFacts::default().status(), KeyStatus::Unknown);
+        assert_eq!(
+            KeyFacts {
+                expired: true,
+                ..Default::default()
+            }
+            .status(),
+            KeyStatus::Expired
+        );
+        // claimed beats expired, and stopped beats claimed
+        let restarting = KeyFacts {
+            claimed_at: Some(location.clone()),
+            expired: true,
+            ..Default::default()
+        };
+        assert_eq!(restarting.status(), claimed);
+        assert_eq!(
+            KeyFacts {
+                stopped: true,
+                claimed_at: Some(location.clone()),
+                ..Default::default()
+            }
+            .status(),
+            KeyStatus::Stopped
+        );
+        // forwarded again after it stopped
+        assert_eq!(
+            KeyFacts {
+                pending: Some(Duration::from_secs(30)),
+                stopped: true,
+                ..Default::default()
+            }
+            .status(),
+            KeyStatus::Pending {
+                expires_in_secs: 30
+            }
+        );
+        // a live session beats everything
+        assert_eq!(
+            KeyFacts {
+                playing: Some((location.clone(), Some(track.clone()))),
+                pending: Some(Duration::from_secs(30)),
+                stopped: true,
+                claimed_at: Some(location.clone()),
+                expired: true,
+            }
+            .status(),
+            KeyStatus::Playing {
+                location: location.clone(),
+                track
+            }
+        );
+        assert_eq!(
+            KeyFacts {
+                playing: Some((location.clone(), None)),
+                stopped: true,
+                ..Default::default()
+            }
+            .status(),
+            claimed
+        );
+    }
+}
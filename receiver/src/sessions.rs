@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Result;
+use player::{Credentials, PlayerOptions, Shutdown, SpotifySession};
+use poise::serenity_prelude::{GuildId, UserId};
+use protocol::{SessionInfo, TrackSummary, VoiceLocation};
+use songbird::tracks::TrackHandle;
+use songbird::Call;
+use tokio::sync::Mutex;
+
+use crate::metrics;
+
+// everything that makes up one guild's playback, so it can all be torn down together
+pub struct PlaybackSession {
+    pub key: String,
+    // whoever ran /play_spotify
+    pub started_by: UserId,
+    pub started_at: SystemTime,
+    pub location: VoiceLocation,
+    spotify: SpotifySession,
+    track: TrackHandle,
+    call: Arc<Mutex<Call>>,
+}
+
+impl PlaybackSession {
+    pub fn new(
+        key: String,
+        started_by: UserId,
+        location: VoiceLocation,
+        spotify: SpotifySession,
+        track: TrackHandle,
+        call: Arc<Mutex<Call>>,
+    ) -> Self {
+        Self {
+            key,
+            started_by,
+            started_at: SystemTime::now(),
+            location,
+            spotify,
+            track,
+            call,
+        }
+    }
+
+    pub fn spotify(&self) -> &SpotifySession {
+        &self.spotify
+    }
+
+    pub fn call(&self) -> &Arc<Mutex<Call>> {
+        &self.call
+    }
+
+    pub fn player_options(&self) -> &PlayerOptions {
+        self.spotify.options()
+    }
+
+    pub fn reusable_creds(&self) -> &Credentials {
+        self.spotify.reusable_creds()
+    }
+
+    pub fn info(&self, guild_id: GuildId) -> SessionInfo {
+        SessionInfo {
+            guild_id: guild_id.0,
+            device_name: self.player_options().device_name.clone(),
+            started_by: self.started_by.0,
+            started_at: self
+                .started_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            track: self.track_summary(),
+        }
+    }
+
+    pub fn track_summary(&self) -> Option<TrackSummary> {
+        self.spotify.now_playing().map(|now_playing| TrackSummary {
+            id: now_playing.track.id,
+            title: now_playing.track.title,
+            artists: now_playing.track.artists,
+            paused: now_playing.paused,
+        })
+    }
+
+    // stops the track and shuts down the player, staying in the voice channel
+    pub async fn stop(self) -> Result<Shutdown> {
+        if let Ok(duration) = self.started_at.elapsed() {
+            metrics::STREAM_DURATION.observe(duration.as_secs_f64());
+        }
+        if let Err(err) = self.track.stop() {
+            // the track may have already ended on its own
+            tracing::debug!(key = ?self.key, ?err, "failed to stop track");
+        }
+        let res = self.spotify.shutdown().await;
+        match &res {
+            Ok(Shutdown::Graceful) => tracing::debug!(key = ?self.key, "stopped playback"),
+            Ok(Shutdown::Forced) => {
+                tracing::warn!(key = ?self.key, "player had to be forcibly shut down")
+            }
+            Err(err) => tracing::warn!(key = ?self.key, ?err, "failed to shut down player"),
+        }
+        res
+    }
+
+    // like stop, but also leaves the voice channel
+    pub async fn leave(self) -> Result<Shutdown> {
+        let call = Arc::clone(&self.call);
+        let res = self.stop().await;
+        call.lock().await.leave().await?;
+        res
+    }
+}
+
+// how long we remember keys that stopped playing
+const STOPPED_HISTORY_TTL: Duration = Duration::from_secs(60 * 60);
+
+// at most one playback session per guild, and at most `max_sessions` in total. the per guild cap isn't
+// configurable: a bot can only be in one voice channel per guild, so there's no second session for it to
+// allow. each player is its own spotify session and resampler, so this is what bounds the receiver's load.
+pub struct SessionManager {
+    sessions: HashMap<GuildId, PlaybackSession>,
+    // guilds that are starting a session and already count towards the cap, with how many reservations they hold
+    reserved: HashMap<GuildId, usize>,
+    max_sessions: usize,
+    // keys whose sessions ended recently, for status queries
+    stopped: HashMap<String, Instant>,
+    // keys whose sessions /reset is starting again, and where
+    restarting: HashMap<String, VoiceLocation>,
+}
+
+impl SessionManager {
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            reserved: HashMap::new(),
+            max_sessions,
+            stopped: HashMap::new(),
+            restarting: HashMap::new(),
+        }
+    }
+
+    pub fn max_sessions(&self) -> usize {
+        self.max_sessions
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    // holds a slot for the guild while its session starts up. None if we're full; a guild that's already playing
+    // can always replace its session.
+    pub fn reserve(this: &Arc<std::sync::Mutex<Self>>, guild_id: GuildId) -> Option<Reservation> {
+        let mut manager = this.lock().unwrap();
+        let in_use = manager.sessions.len()
+            + manager
+                .reserved
+                .keys()
+                .filter(|reserved| !manager.sessions.contains_key(reserved))
+                .count();
+        let taken =
+            manager.sessions.contains_key(&guild_id) || manager.reserved.contains_key(&guild_id);
+        if !taken && in_use >= manager.max_sessions {
+            return None;
+        }
+        *manager.reserved.entry(guild_id).or_default() += 1;
+        Some(Reservation {
+            sessions: Arc::clone(this),
+            guild_id,
+        })
+    }
+
+    // returns the session this one replaced, which the caller should stop
+    pub fn insert(
+        &mut self,
+        guild_id: GuildId,
+        session: PlaybackSession,
+    ) -> Option<PlaybackSession> {
+        self.stopped.remove(&session.key);
+        self.restarting.remove(&session.key);
+        let key = session.key.clone();
+        let previous = self.sessions.insert(guild_id, session);
+        if let Some(previous) = previous.as_ref().filter(|previous| previous.key != key) {
+            self.mark_stopped(&previous.key);
+        }
+        previous
+    }
+
+    pub fn get(&self, guild_id: GuildId) -> Option<&PlaybackSession> {
+        self.sessions.get(&guild_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (GuildId, &PlaybackSession)> {
+        self.sessions
+            .iter()
+            .map(|(guild_id, session)| (*guild_id, session))
+    }
+
+    // oldest first, with their keys
+    pub fn list(&self) -> Vec<(String, SessionInfo)> {
+        let mut sessions: Vec<_> = self
+            .iter()
+            .map(|(guild_id, session)| (session.key.clone(), session.info(guild_id)))
+            .collect();
+        sessions.sort_by_key(|(_, info)| info.started_at);
+        sessions
+    }
+
+    pub fn find_by_key(&self, key: &str) -> Option<&PlaybackSession> {
+        self.sessions.values().find(|session| session.key == key)
+    }
+
+    pub fn remove(&mut self, guild_id: GuildId) -> Option<PlaybackSession> {
+        let session = self.sessions.remove(&guild_id)?;
+        self.mark_stopped(&session.key);
+        Some(session)
+    }
+
+    // for /reset, which starts it again: the key isn't stopped, just on its way back
+    pub fn take_for_restart(&mut self, guild_id: GuildId) -> Option<PlaybackSession> {
+        let session = self.sessions.remove(&guild_id)?;
+        self.restarting
+            .insert(session.key.clone(), session.location.clone());
+        Some(session)
+    }
+
+    pub fn restarting(&self, key: &str) -> Option<&VoiceLocation> {
+        self.restarting.get(key)
+    }
+
+    // only removes the session if it's still playing the given key
+    pub fn remove_if_key(&mut self, guild_id: GuildId, key: &str) -> Option<PlaybackSession> {
+        match self.sessions.get(&guild_id) {
+            Some(session) if session.key == key => self.remove(guild_id),
+            _ => None,
+        }
+    }
+
+    // also for keys whose session never got going
+    pub fn mark_stopped(&mut self, key: &str) {
+        self.stopped
+            .retain(|_, at| at.elapsed() < STOPPED_HISTORY_TTL);
+        self.stopped.insert(key.to_string(), Instant::now());
+        self.restarting.remove(key);
+    }
+
+    pub fn is_stopped(&self, key: &str) -> bool {
+        self.stopped.contains_key(key)
+    }
+}
+
+// a slot from SessionManager::reserve. hold it until the session is inserted; if starting it fails, dropping it gives
+// the slot back.
+pub struct Reservation {
+    sessions: Arc<std::sync::Mutex<SessionManager>>,
+    guild_id: GuildId,
+}
+
+impl Reservation {
+    pub fn guild_id(&self) -> GuildId {
+        self.guild_id
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut manager = self.sessions.lock().unwrap();
+        if let Some(count) = manager.reserved.get_mut(&self.guild_id) {
+            *count -= 1;
+            if *count == 0 {
+                manager.reserved.remove(&self.guild_id);
+            }
+        }
+    }
+}
+
+// a user-facing summary of how a teardown went
+pub fn describe_shutdown(res: &Result<Shutdown>) -> &'static str {
+    match res {
+        Ok(Shutdown::Graceful) => "the player shut down cleanly",
+        Ok(Shutdown::Forced) => "the player didn't respond and had to be killed",
+        Err(_) => "the player failed to shut down, check the logs",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+        let manager = Arc::new(std::sync::Mutex::new(SessionManager::new(2)));
+        let a = SessionManager::reserve(&manager, GuildId(1)).unwrap();
+        let b = SessionManager::reserve(&manager, GuildId(2)).unwrap();
+        assert!(SessionManager::reserve(&manager, GuildId(3)).is_none());
+        // a guild that already holds a slot doesn't need another
+        assert!(SessionManager::reserve(&manager, GuildId(2)).is_some());
+
+        drop(a);
+        let c = SessionManager::reserve(&manager, GuildId(3)).unwrap();
+        assert!(SessionManager::reserve(&manager, GuildId(1)).is_none());
+        drop((b, c));
+        assert!(SessionManager::reserve(&manager, GuildId(1)).is_some());
+    }
+}